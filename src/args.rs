@@ -1,10 +1,57 @@
 use std::env;
 use std::collections::HashSet;
 use std::mem::{Discriminant, discriminant};
+use crate::asm::{Target, RET_STACK_LIMIT};
+use crate::emit::Emit;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Subcommand {
+    Com,
+    Sim,
+    Check,
+    Run,
+    Test,
+    Lsp,
+    Fmt,
+    Repl,
+    Debug,
+}
+
+impl Subcommand {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "com" => Some(Subcommand::Com),
+            "sim" => Some(Subcommand::Sim),
+            "check" => Some(Subcommand::Check),
+            "run" => Some(Subcommand::Run),
+            "test" => Some(Subcommand::Test),
+            "lsp" => Some(Subcommand::Lsp),
+            "fmt" => Some(Subcommand::Fmt),
+            "repl" => Some(Subcommand::Repl),
+            "debug" => Some(Subcommand::Debug),
+            _ => None,
+        }
+    }
+}
 
 pub enum ArgCommand {
-    Run(String),
+    File(String),
+    Sub(Subcommand),
     Version,
+    Help,
+    Target(Target),
+    Optimize(u8),
+    RetStack(u64),
+    IncludeDir(String),
+    Emit(Emit),
+    Json,
+    Debug,
+    Output(String),
+    Keep(bool),
+    Args(Vec<String>),
+    Record,
+    Native,
+    CheckFormat,
 }
 
 struct ArgsParse {
@@ -20,12 +67,31 @@ impl ArgsParse {
         }
     }
 
-    fn add(&mut self, cmd: ArgCommand) -> bool {
-        if self.used_commands.contains(&discriminant(&cmd)) { return false; }
+    /*
+        Every kind of argument may only be given once, except -I. `arg` is
+        what was on the command line, for the error.
+     */
+    fn add(&mut self, cmd: ArgCommand, arg: &str) -> Result<(), String> {
+        if let ArgCommand::IncludeDir(_) = cmd {
+            self.commands.push(cmd);
+            return Ok(());
+        }
+
+        if self.used_commands.contains(&discriminant(&cmd)) {
+            return Err(match cmd {
+                ArgCommand::File(_) => format!("only one Ktnack file or directory can be given, found another: {}", arg),
+                ArgCommand::Sub(_) => format!("only one subcommand can be given, found another: {}", arg),
+                _ => format!("`{}` repeats or conflicts with an earlier argument", arg),
+            });
+        }
 
         self.used_commands.insert(discriminant(&cmd));
         self.commands.push(cmd);
-        return true;
+        return Ok(());
+    }
+
+    fn has_file(&self) -> bool {
+        self.commands.iter().any(|x| matches!(x, ArgCommand::File(_) | ArgCommand::Sub(_)))
     }
 
     fn complete(self) -> Vec<ArgCommand> {
@@ -33,17 +99,91 @@ impl ArgsParse {
     }
 }
 
-pub fn get_env_arg_cmds() -> Vec<ArgCommand> {
+/*
+    The value of a flag, either inline as `--flag=value` or as the next
+    argument.
+ */
+fn flag_value<'a>(arg: &'a str, long: &str, it: &mut dyn Iterator<Item = &'a String>) -> Result<&'a str, String> {
+    if let Some(value) = arg.strip_prefix(long).and_then(|x| x.strip_prefix('=')) {
+        return Ok(value);
+    }
+
+    match it.next() {
+        Some(value) => Ok(value.as_str()),
+        None => Err(format!("missing value after `{}`", arg)),
+    }
+}
+
+fn is_flag(arg: &str, long: &str, short: &str) -> bool {
+    arg == long || (!short.is_empty() && arg == short) || arg.starts_with(format!("{}=", long).as_str())
+}
+
+pub fn get_env_arg_cmds() -> Result<Vec<ArgCommand>, String> {
     let args: Vec<String> = env::args().collect();
     let mut parse = ArgsParse::new();
-    
-    for arg in args.iter().skip(1) {
-        if arg == "--version" || arg == "-v" {
-            parse.add(ArgCommand::Version);
+
+    let mut it = args.iter().skip(1);
+    while let Some(arg) = it.next() {
+        if arg == "--" {
+            parse.add(ArgCommand::Args(it.by_ref().cloned().collect()), arg)?;
+        } else if arg == "--version" || arg == "-v" {
+            parse.add(ArgCommand::Version, arg)?;
+        } else if arg == "--help" || arg == "-h" {
+            parse.add(ArgCommand::Help, arg)?;
+        } else if arg == "--sim" {
+            parse.add(ArgCommand::Sub(Subcommand::Sim), arg)?;
+        } else if let Some(level) = arg.strip_prefix("-O") {
+            match level {
+                "" => parse.add(ArgCommand::Optimize(1), arg)?,
+                "0" | "1" | "2" => parse.add(ArgCommand::Optimize(level.parse().unwrap()), arg)?,
+                _ => return Err(format!("unknown optimization level: {} (expected -O0, -O1 or -O2)", arg)),
+            };
+        } else if let Some(dir) = arg.strip_prefix("-I") {
+            let dir = if dir.is_empty() { flag_value(arg, "-I", &mut it)? } else { dir };
+            parse.add(ArgCommand::IncludeDir(dir.to_string()), arg)?;
+        } else if is_flag(arg, "--emit", "") {
+            let name = flag_value(arg, "--emit", &mut it)?;
+            match Emit::from_name(name) {
+                Some(kind) => parse.add(ArgCommand::Emit(kind), arg)?,
+                None => return Err(format!("unknown emit stage: {} (expected tokens, expanded, ops or asm)", name)),
+            }
+        } else if is_flag(arg, "--ret-stack", "") {
+            let value = flag_value(arg, "--ret-stack", &mut it)?;
+            match value.parse::<u64>().ok().filter(|x| (1..=RET_STACK_LIMIT).contains(x)) {
+                Some(size) => parse.add(ArgCommand::RetStack(size), arg)?,
+                None => return Err(format!("invalid return stack size: {} (expected 1 to {} calls)", value, RET_STACK_LIMIT)),
+            }
+        } else if arg == "--json" {
+            parse.add(ArgCommand::Json, arg)?;
+        } else if arg == "--debug" {
+            parse.add(ArgCommand::Debug, arg)?;
+        } else if is_flag(arg, "--output", "-o") {
+            let path = flag_value(arg, "--output", &mut it)?;
+            parse.add(ArgCommand::Output(path.to_string()), arg)?;
+        } else if arg == "--keep" {
+            parse.add(ArgCommand::Keep(true), arg)?;
+        } else if arg == "--clean" {
+            parse.add(ArgCommand::Keep(false), arg)?;
+        } else if arg == "--record" {
+            parse.add(ArgCommand::Record, arg)?;
+        } else if arg == "--native" {
+            parse.add(ArgCommand::Native, arg)?;
+        } else if arg == "--check" {
+            parse.add(ArgCommand::CheckFormat, arg)?;
+        } else if is_flag(arg, "--target", "-t") {
+            let name = flag_value(arg, "--target", &mut it)?;
+            match Target::from_name(name) {
+                Some(target) => parse.add(ArgCommand::Target(target), arg)?,
+                None => return Err(format!("unknown target: {} (expected win64 or linux)", name)),
+            }
+        } else if arg.starts_with('-') && arg.len() > 1 {
+            return Err(format!("unknown flag `{}`", arg));
+        } else if let (false, Some(sub)) = (parse.has_file(), Subcommand::from_name(arg)) {
+            parse.add(ArgCommand::Sub(sub), arg)?;
         } else {
-            parse.add(ArgCommand::Run(arg.clone()));
+            parse.add(ArgCommand::File(arg.clone()), arg)?;
         }
     }
 
-    return parse.complete();
-}
\ No newline at end of file
+    return Ok(parse.complete());
+}
@@ -1,77 +1,242 @@
 use std::io;
-use std::io::{Write, ErrorKind};
-use std::fs::File;
+use std::io::ErrorKind;
+use std::fs;
 use std::process::Command;
+use crate::ltypes::{LLoc, MEM_LIMIT};
+use crate::error::CompileError;
 
+/*
+    How many procedure calls deep a program may go before it stops with a
+    return stack overflow, natively and in the simulator, unless
+    --ret-stack asks for another depth. Each call takes 8 bytes, and the
+    most that can be asked for is as much as the memory buffer may take.
+ */
+pub const RET_STACK_SIZE: u64 = 8192;
+pub const RET_STACK_LIMIT: u64 = MEM_LIMIT / 8;
+
+const RET_STACK_OVERFLOW: &str = "error: return stack overflow, procedures are nested too deep";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Target {
+    Win64,
+    Linux,
+}
+
+impl Target {
+    pub fn host() -> Self {
+        if cfg!(target_os = "windows") {
+            Target::Win64
+        } else {
+            Target::Linux
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "win64" | "windows" => Some(Target::Win64),
+            "linux" | "elf64" => Some(Target::Linux),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Target::Win64 => "win64",
+            Target::Linux => "linux",
+        }
+    }
+
+    pub fn exe_name(&self, name: &str) -> String {
+        match self {
+            Target::Win64 => format!("{}.exe", name),
+            Target::Linux => name.to_string(),
+        }
+    }
+
+    pub fn obj_name(&self, name: &str) -> String {
+        match self {
+            Target::Win64 => format!("{}.obj", name),
+            Target::Linux => format!("{}.o", name),
+        }
+    }
+}
+
+/*
+    The assembly for a program, built up in memory. Nothing is written to
+    disk until the caller decides what to do with it.
+ */
 pub struct AsmFile {
-    name: String,
-    filename: String,
-    handle: File,
+    text: String,
+    target: Target,
+    loc: Option<LLoc>,
 }
 
 impl AsmFile {
-    fn new(name: &str) -> Self {
-        let filename = format!("{}.asm", name); 
-        let output = File::create(filename.clone());
-        let mut output = match output {
-            Ok(file) => file,
-            Err(error) => {
-                panic!("Problem creating ASM file : {:?}", error);
-            }
-        };
-
+    fn new(target: Target, mem_size: u64, ret_stack: u64) -> Self {
         let mut result = Self {
-            name: name.to_string(),
-            filename: filename,
-            handle: output,
+            text: String::new(),
+            target,
+            loc: None,
         };
 
-        result.write("BITS 64\n");
-        result.write("global main\n");
-        result.write("extern printf\n");
-        result.write("segment .data\n");
-        result.write("    fmt     db \"%ld\", 10, 0\n");
-        result.write("    putc    db 0, 0\n");
-        result.write("    putcf   db \"%s\", 0\n");
-        result.write("segment .bss\n");
-        result.write("    membuf  resb 640 * 1024\n");
-        result.write("segment .text\n");
-        result.write("log:\n");
-        result.write("    sub     rsp, 32\n");
-        result.write("    mov     rdx, rcx\n");
-        result.write("    lea     rcx, [rel fmt]\n");
-        result.write("    call    printf\n");
-        result.write("    add     rsp, 32\n");
-        result.write("    ret\n");
-        result.write("puts:\n");
-        result.write("    sub     rsp, 32\n");
-        result.write("    mov     [rel putc], cl\n");
-        result.write("    lea     rdx, [rel putc]\n");
-        result.write("    lea     rcx, [rel putcf]\n");
-        result.write("    call    printf\n");
-        result.write("    add     rsp, 32\n");
-        result.write("    ret\n");
-        result.write("main:\n");
-
-        result
-    }
-
-    pub fn close(&mut self) -> String {
-        self.handle.flush().expect("Failed to flush ASM file!");
-        
-        self.name.clone()
+        match target {
+            Target::Win64 => result.win64_prologue(mem_size, ret_stack),
+            Target::Linux => result.linux_prologue(mem_size, ret_stack),
+        }
+
+        return result;
+    }
+
+    fn win64_prologue(&mut self, mem_size: u64, ret_stack: u64) {
+        self.write("BITS 64\n");
+        self.write("global main\n");
+        self.write("extern printf\n");
+        self.write("extern exit\n");
+        self.write("extern _write\n");
+        self.write("segment .data\n");
+        self.write("    fmt     db \"%ld\", 10, 0\n");
+        self.write("    putc    db 0, 0\n");
+        self.write("    putcf   db \"%s\", 0\n");
+        self.write("segment .bss\n");
+        self.write(format!("    membuf  resb {}\n", mem_size).as_str());
+        self.write("    entry_rsp resq 1\n");
+        self.write("    args_argc resq 1\n");
+        self.write("    args_argv resq 1\n");
+        self.write("    args_envp resq 1\n");
+        self.write("    ret_stack_rsp resq 1\n");
+        self.write(format!("    ret_stack resq {}\n", ret_stack).as_str());
+        self.write("ret_stack_end:\n");
+        self.write("segment .text\n");
+        self.write("log:\n");
+        self.write("    sub     rsp, 32\n");
+        self.write("    mov     rdx, rcx\n");
+        self.write("    lea     rcx, [rel fmt]\n");
+        self.write("    call    printf\n");
+        self.write("    add     rsp, 32\n");
+        self.write("    ret\n");
+        self.write("puts:\n");
+        self.write("    sub     rsp, 32\n");
+        self.write("    mov     [rel putc], cl\n");
+        self.write("    lea     rdx, [rel putc]\n");
+        self.write("    lea     rcx, [rel putcf]\n");
+        self.write("    call    printf\n");
+        self.write("    add     rsp, 32\n");
+        self.write("    ret\n");
+        self.write("main:\n");
+        self.write("    mov     [rel entry_rsp], rsp\n");
+        self.write("    movsxd  rax, ecx\n");
+        self.write("    mov     [rel args_argc], rax\n");
+        self.write("    mov     [rel args_argv], rdx\n");
+        self.write("    mov     [rel args_envp], r8\n");
+        self.write("    lea     rax, [rel ret_stack_end]\n");
+        self.write("    mov     [rel ret_stack_rsp], rax\n");
+    }
+
+    /*
+        System V ABI: arguments go in rdi/rsi, al holds the vector register
+        count for variadic calls, and rsp must be 16 byte aligned at the call.
+        The data stack lives on rsp, so the helpers realign it themselves.
+        Ops still pass the helper argument in rcx like on win64.
+     */
+    fn linux_prologue(&mut self, mem_size: u64, ret_stack: u64) {
+        self.write("BITS 64\n");
+        self.write("default rel\n");
+        self.write("global main\n");
+        self.write("extern printf\n");
+        self.write("extern fflush\n");
+        self.write("extern exit\n");
+        self.write("extern write\n");
+        self.write("segment .data\n");
+        self.write("    fmt     db \"%ld\", 10, 0\n");
+        self.write("    putc    db 0, 0\n");
+        self.write("    putcf   db \"%s\", 0\n");
+        self.write("segment .bss\n");
+        self.write(format!("    membuf  resb {}\n", mem_size).as_str());
+        self.write("    entry_rsp resq 1\n");
+        self.write("    args_argc resq 1\n");
+        self.write("    args_argv resq 1\n");
+        self.write("    args_envp resq 1\n");
+        self.write("    ret_stack_rsp resq 1\n");
+        self.write(format!("    ret_stack resq {}\n", ret_stack).as_str());
+        self.write("ret_stack_end:\n");
+        self.write("segment .text\n");
+        self.write("flush:\n");
+        self.write("    push    rbp\n");
+        self.write("    mov     rbp, rsp\n");
+        self.write("    and     rsp, -16\n");
+        self.write("    xor     edi, edi\n");
+        self.write("    call    fflush wrt ..plt\n");
+        self.write("    mov     rsp, rbp\n");
+        self.write("    pop     rbp\n");
+        self.write("    ret\n");
+        self.write("log:\n");
+        self.write("    push    rbp\n");
+        self.write("    mov     rbp, rsp\n");
+        self.write("    and     rsp, -16\n");
+        self.write("    mov     rsi, rcx\n");
+        self.write("    lea     rdi, [rel fmt]\n");
+        self.write("    xor     eax, eax\n");
+        self.write("    call    printf wrt ..plt\n");
+        self.write("    mov     rsp, rbp\n");
+        self.write("    pop     rbp\n");
+        self.write("    ret\n");
+        self.write("puts:\n");
+        self.write("    push    rbp\n");
+        self.write("    mov     rbp, rsp\n");
+        self.write("    and     rsp, -16\n");
+        self.write("    mov     [rel putc], cl\n");
+        self.write("    lea     rsi, [rel putc]\n");
+        self.write("    lea     rdi, [rel putcf]\n");
+        self.write("    xor     eax, eax\n");
+        self.write("    call    printf wrt ..plt\n");
+        self.write("    mov     rsp, rbp\n");
+        self.write("    pop     rbp\n");
+        self.write("    ret\n");
+        self.write("main:\n");
+        self.write("    mov     [rel entry_rsp], rsp\n");
+        self.write("    movsxd  rax, edi\n");
+        self.write("    mov     [rel args_argc], rax\n");
+        self.write("    mov     [rel args_argv], rsi\n");
+        self.write("    mov     [rel args_envp], rdx\n");
+        self.write("    lea     rax, [rel ret_stack_end]\n");
+        self.write("    mov     [rel ret_stack_rsp], rax\n");
+    }
+
+    pub fn target(&self) -> Target {
+        self.target
     }
 
     pub fn write(&mut self, text: &str) {
-        write!(self.handle, "{}", text).expect("Failed to write to file!");
+        self.text.push_str(text);
     }
 
     pub fn code(&mut self, text: &str) {
         self.write(format!("    {}\n", text).as_str());
     }
 
+    /*
+        push only takes a sign extended 32 bit immediate, so wider values
+        go through rax.
+     */
+    pub fn push_imm(&mut self, value: i64) {
+        if value >= i32::MIN as i64 && value <= i32::MAX as i64 {
+            self.code(format!("push {}", value).as_str());
+        } else {
+            self.code(format!("mov rax, {}", value).as_str());
+            self.code("push rax");
+        }
+    }
+
+    pub fn set_loc(&mut self, loc: Option<LLoc>) {
+        self.loc = loc;
+    }
+
     pub fn title(&mut self, text: &str) {
-        self.write(format!("    ;; -- {} --\n", text).as_str());
+        let text = match &self.loc {
+            Some(loc) => format!("    ;; -- {} -- {}\n", text, loc),
+            None => format!("    ;; -- {} --\n", text),
+        };
+        self.write(text.as_str());
     }
 
     pub fn addr(&mut self, ip: u64) {
@@ -83,43 +248,118 @@ impl AsmFile {
     }
 }
 
-pub fn pre_compile(name: &str) -> AsmFile {
-    println!("Generating ASM from Ktnack code...");
-    return AsmFile::new(name);
+pub fn pre_compile(target: Target, mem_size: u64, ret_stack: u64) -> AsmFile {
+    return AsmFile::new(target, mem_size, ret_stack);
 }
 
-pub fn post_compile(mut file: AsmFile) -> bool {
+fn run_tool(command: &mut Command, name: &str) -> Result<bool, String> {
+    match command.status() {
+        Ok(status) => Ok(status.success()),
+        Err(error) => Err(format!("could not run `{}`: {}", name, error)),
+    }
+}
+
+/*
+    Writes what follows the last op, returning the finished assembly.
+ */
+pub fn end_asm(mut file: AsmFile) -> String {
+    file.write("segment .text\n");
     file.write("addr_eof:\n");
-    file.write("    ret\n");
+    file.write("    xor     ecx, ecx\n");
 
-    let name = file.name.to_string();
-    let file = file.close();
+    /*
+        Exits with the status in rcx from wherever the program is, whatever
+        is left on the data stack. exit also flushes printf's output.
+     */
+    file.write("exit_program:\n");
+    file.write("    mov     rsp, [rel entry_rsp]\n");
+    file.write("    and     rsp, -16\n");
+    match file.target {
+        Target::Win64 => {
+            file.write("    sub     rsp, 32\n");
+            file.write("    call    exit\n");
+        },
+        Target::Linux => {
+            file.write("    mov     edi, ecx\n");
+            file.write("    call    exit wrt ..plt\n");
+        },
+    }
 
-    println!("Building ASM...");
-    let status = Command::new("nasm")
-                                .args(&["-f", "win64", "-o", format!("{}.obj", name).as_str(), format!("{}.asm", name).as_str()])
-                                .status()
-                                .expect("Failed to assemble code!");
+    /*
+        A call found the return stack full. Reports it on stderr and exits
+        with status 1, from wherever the program is.
+     */
+    file.write("ret_stack_overflow:\n");
+    file.write("    mov     rsp, [rel entry_rsp]\n");
+    file.write("    and     rsp, -16\n");
+    match file.target {
+        Target::Win64 => {
+            file.write("    sub     rsp, 32\n");
+            file.write("    mov     ecx, 2\n");
+            file.write("    lea     rdx, [rel overflow_msg]\n");
+            file.write(format!("    mov     r8d, {}\n", RET_STACK_OVERFLOW.len() + 1).as_str());
+            file.write("    call    _write\n");
+            file.write("    add     rsp, 32\n");
+        },
+        Target::Linux => {
+            file.write("    mov     edi, 2\n");
+            file.write("    lea     rsi, [rel overflow_msg]\n");
+            file.write(format!("    mov     edx, {}\n", RET_STACK_OVERFLOW.len() + 1).as_str());
+            file.write("    call    write wrt ..plt\n");
+        },
+    }
+    file.write("    mov     ecx, 1\n");
+    file.write("    jmp     exit_program\n");
+    file.write("segment .data\n");
+    file.write(format!("    overflow_msg db \"{}\", 10\n", RET_STACK_OVERFLOW).as_str());
+
+    if file.target == Target::Linux {
+        file.write("section .note.GNU-stack noalloc noexec nowrite progbits\n");
+    }
+
+    return file.text;
+}
 
-    if !status.success() {
-        println!("Failed to build ASM!");
-        return false;
+pub fn post_compile(name: &str, exe_name: &str, target: Target) -> Result<(), CompileError> {
+    let asm_name = format!("{}.asm", name);
+    let obj_name = target.obj_name(name);
+
+    eprintln!("Building ASM...");
+    let format = match target {
+        Target::Win64 => "win64",
+        Target::Linux => "elf64",
+    };
+    let success = run_tool(Command::new("nasm")
+                                .args(["-f", format, "-o", obj_name.as_str(), asm_name.as_str()]), "nasm")
+                                .map_err(CompileError::AssemblerFailure)?;
+
+    if !success {
+        return Err(CompileError::AssemblerFailure(format!("nasm could not assemble `{}`", asm_name)));
     }
 
-    println!("Linking program...");
+    eprintln!("Linking program...");
 
-    let status = Command::new("link")
-                                .args(&[format!("{}.obj", name).as_str(), "/subsystem:console", "kernel32.lib", "msvcrt.lib", "legacy_stdio_definitions.lib", format!("/out:{}.exe", name).as_str()])
-                                .status()
-                                .expect("Failed to link code!");
+    let success = match target {
+        Target::Win64 => run_tool(Command::new("link")
+                                .args([obj_name.as_str(), "/subsystem:console", "kernel32.lib", "msvcrt.lib", "legacy_stdio_definitions.lib", format!("/out:{}", exe_name).as_str()]), "link"),
+        Target::Linux => run_tool(Command::new("cc")
+                                .args(["-o", exe_name, obj_name.as_str()]), "cc"),
+    }.map_err(CompileError::LinkerFailure)?;
 
-    if !status.success() {
-        println!("Failed to link program!");
-        return false;
+    if !success {
+        return Err(CompileError::LinkerFailure(format!("could not link `{}`", obj_name)));
     }
 
-    println!("Compilation successful!");
-    println!("Binary located as: {}.exe", name);
+    eprintln!("Compilation successful!");
+    eprintln!("Binary located as: {}", exe_name);
 
-    return true;    
-}
\ No newline at end of file
+    return Ok(());
+}
+
+/*
+    Removes the .asm and object files left by compiling, if there are any.
+ */
+pub fn remove_intermediates(name: &str, target: Target) {
+    let _ = fs::remove_file(format!("{}.asm", name));
+    let _ = fs::remove_file(target.obj_name(name));
+}
@@ -0,0 +1,200 @@
+use crate::ltypes::*;
+use crate::compile::Compiler;
+use crate::error::CompileError;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+/*
+    Values taken and left by each procedure, None for one that never
+    returns because every path through it exits.
+ */
+type Effects = HashMap<u64, Option<(usize, usize)>>;
+
+/*
+    Result of walking one body. `low` is the deepest the body reaches below
+    its starting depth and `exit` the depth relative to the start when it
+    returns, if any path gets there. `blocked` is set when a path was cut
+    short by a call to a procedure without a known effect.
+ */
+struct Walk {
+    low: i64,
+    exit: Option<i64>,
+    blocked: bool,
+}
+
+fn op_loc(compiler: &Compiler, ip: u64) -> LLoc {
+    compiler.op(ip).unwrap().loc.clone()
+}
+
+/*
+    Walks every path through a body keeping track of the stack depth.
+    Each ip may only ever be reached with one depth, which is what makes both
+    branches of an if agree and loop bodies keep the depth they started with.
+    Ops are visited lowest ip first, so both sides of a join are seen before
+    anything after it and a mismatch is reported where it happens.
+
+    The top level program starts empty and must end empty. A procedure body
+    starts at depth 0 and may dig below it, which becomes its inputs, so
+    depths inside one can be negative and are reported as such. Calls to
+    procedures without a known effect yet end the path they are on.
+ */
+fn walk(compiler: &Compiler, len: u64, start: u64, effects: &Effects, in_proc: bool) -> Result<Walk, CompileError> {
+    let mut depths: Vec<Option<i64>> = vec![None; len as usize + 1];
+    let mut work: BinaryHeap<Reverse<(u64, i64, Option<u64>)>> = BinaryHeap::new();
+    work.push(Reverse((start, 0, None)));
+
+    let mut result = Walk {
+        low: 0,
+        exit: None,
+        blocked: false,
+    };
+
+    while let Some(Reverse((ip, depth, from))) = work.pop() {
+        let mut arrivals = vec![(depth, from)];
+        while let Some(Reverse((next_ip, next_depth, next_from))) = work.peek().cloned() {
+            if next_ip != ip {
+                break;
+            }
+            work.pop();
+            arrivals.push((next_depth, next_from));
+        }
+
+        let expected = depths[ip as usize].unwrap_or(depth);
+        for (depth, from) in arrivals {
+            if expected == depth {
+                continue;
+            }
+
+            let loc = match from {
+                Some(from) => op_loc(compiler, from),
+                None => op_loc(compiler, ip),
+            };
+
+            if let Some(LOp { op: LOpType::While, .. }) = compiler.op(ip) {
+                return Err(CompileError::LoopDepthChange(loc, expected, depth));
+            }
+
+            return Err(CompileError::BranchDepthMismatch(loc, expected, depth));
+        }
+
+        if depths[ip as usize].is_some() {
+            continue;
+        }
+
+        let depth = expected;
+        depths[ip as usize] = Some(depth);
+
+        let op = match compiler.op(ip) {
+            Some(op) => op,
+            None => {
+                if depth != 0 {
+                    return Err(CompileError::StackNotEmpty(op_loc(compiler, len - 1), depth as usize));
+                }
+                continue;
+            }
+        };
+
+        let (ins, outs) = match &op.op {
+            LOpType::Call(_, proc_ip) => match effects.get(proc_ip) {
+                Some(Some(effect)) => *effect,
+                Some(None) => continue,
+                None => {
+                    result.blocked = true;
+                    continue;
+                },
+            },
+            LOpType::Ret => {
+                result.exit = Some(depth);
+                continue;
+            },
+            _ => op.op.stack_effect(),
+        };
+
+        let low = depth - ins as i64;
+        if low < 0 && !in_proc {
+            return Err(CompileError::StackUnderflow(op.loc.clone(), op.word.clone(), ins, depth as usize));
+        }
+
+        result.low = result.low.min(low);
+
+        let depth = low + outs as i64;
+        for next in op.op.successors(ip) {
+            work.push(Reverse((next, depth, Some(ip))));
+        }
+    }
+
+    return Ok(result);
+}
+
+fn proc_effect(compiler: &Compiler, len: u64, proc_ip: u64, effects: &Effects) -> Result<Option<Option<(usize, usize)>>, CompileError> {
+    let walk = walk(compiler, len, proc_ip + 1, effects, true)?;
+    match walk.exit {
+        Some(exit) => Ok(Some(Some(((-walk.low) as usize, (exit - walk.low) as usize)))),
+        None if !walk.blocked => Ok(Some(None)),
+        None => Ok(None),
+    }
+}
+
+/*
+    Procedure effects are inferred from their bodies. Recursive calls are
+    skipped until the non-recursive paths give the procedure an effect, after
+    which every body is walked once more to make sure the effect holds.
+ */
+fn infer_proc_effects(compiler: &Compiler, len: u64) -> Result<Effects, CompileError> {
+    let mut procs: Vec<u64> = Vec::new();
+    for ip in 0..len {
+        if let Some(LOp { op: LOpType::Proc(_, _), .. }) = compiler.op(ip) {
+            procs.push(ip);
+        }
+    }
+
+    let mut effects: Effects = HashMap::new();
+    for _ in 0..=procs.len() {
+        let mut changed = false;
+        for proc_ip in procs.iter() {
+            if effects.contains_key(proc_ip) {
+                continue;
+            }
+
+            if let Some(effect) = proc_effect(compiler, len, *proc_ip, &effects)? {
+                effects.insert(*proc_ip, effect);
+                changed = true;
+            }
+        }
+
+        if !changed {
+            break;
+        }
+    }
+
+    for proc_ip in procs.iter() {
+        let op = compiler.op(*proc_ip).unwrap();
+        let name = match &op.op {
+            LOpType::Proc(name, _) => name.clone(),
+            _ => String::new(),
+        };
+
+        let expected = effects.get(proc_ip).cloned();
+        if expected.is_none() || proc_effect(compiler, len, *proc_ip, &effects)? != expected {
+            return Err(CompileError::UnknownStackEffect(op.loc.clone(), name));
+        }
+    }
+
+    return Ok(effects);
+}
+
+pub fn check_stack(compiler: &Compiler) -> Result<(), CompileError> {
+    let mut len: u64 = 0;
+    while compiler.op(len).is_some() {
+        len += 1;
+    }
+
+    if len == 0 {
+        return Ok(());
+    }
+
+    let effects = infer_proc_effects(compiler, len)?;
+    walk(compiler, len, 0, &effects, false)?;
+
+    return Ok(());
+}
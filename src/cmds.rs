@@ -5,11 +5,60 @@ pub fn cmd_version() {
     println!("{} Version: v{}", NAME, VERSION);    
 }
 
+pub fn cmd_usage() -> &'static str {
+    return "\
+Usage: ktnack [subcommand] [options] <file.ktnck> [-- args...]
+       ktnack test [options] <dir>
+       ktnack fmt [--check] <file.ktnck or dir>
+       ktnack lsp [options]
+       ktnack repl [options]
+
+Subcommands:
+    com                 Compile the program to an executable (default)
+    sim                 Run the program in the simulator
+    check               Only check the program for errors
+    run                 Compile the program and run it
+    test                Run every program in a directory and compare its
+                        output and exit status with its .expect file
+    fmt                 Reformat a file, or every file in a directory
+    lsp                 Run a language server over stdin and stdout
+    repl                Run code as it is typed, in the simulator
+    debug               Step through the program in the simulator, with
+                        breakpoints
+
+Options:
+    -o, --output <path> Where to write the executable
+    --keep              Keep the .asm and object files (default for com)
+    --clean             Delete the .asm and object files (default for run)
+    -t, --target <name> Target to compile for, win64 or linux
+    -O0, -O1, -O2       Optimization level
+    -I <dir>            Also look for included files in <dir>
+    --ret-stack <calls> How many procedure calls deep the program may go
+                        (default 8192)
+    --emit <stage>      Print tokens, expanded, ops or asm instead of building
+    --json              Print --emit output as JSON
+    --debug             Print extra information while compiling
+    -v, --version       Print the version
+    -h, --help          Print this help
+    --record            Write the .expect files for test instead of comparing
+    --native            Compile and run the programs for test instead of
+                        simulating them
+    --check             List the files fmt would change, without changing
+                        them
+    -- args...          Arguments passed to the program by run, sim and debug
+";
+}
+
 pub fn cmd_handle_cmd(cmd_arg: &ArgCommand) -> bool {
     if let ArgCommand::Version = cmd_arg {
         cmd_version();
         return true;
     }
 
+    if let ArgCommand::Help = cmd_arg {
+        print!("{}", cmd_usage());
+        return true;
+    }
+
     return false;
-}
\ No newline at end of file
+}
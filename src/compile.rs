@@ -1,27 +1,52 @@
 use crate::ltypes::*;
-use crate::src::load_and_lex_code;
+use crate::src::{load_code, expand_code, lex_code};
 use crate::asm::*;
+use crate::sim::Simulator;
+use crate::error::CompileError;
+use crate::check::check_stack;
+use crate::optimize::{optimize, jump_targets};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
 use std::path::Path;
 
 pub struct Compiler {
-    pub code: Vec<LOpType>,
+    pub code: Vec<LOp>,
+    pub memory: LMemory,
     name: String,
+    exe: String,
+    target: Target,
+    ret_stack: u64,
 }
 
 impl Compiler {
-    pub fn new(path: &str) -> Self {
+    pub fn new(path: &str, target: Target, include_dirs: &[String]) -> Result<Self, CompileError> {
+        let tokens = load_code(path, include_dirs)?;
+        return Compiler::from_words(path, target, expand_code(&tokens)?);
+    }
+
+    /*
+        Builds the ops from code that is already loaded and expanded.
+     */
+    pub fn from_words(path: &str, target: Target, words: Vec<LWord>) -> Result<Self, CompileError> {
         let file_name = Path::new(path)
             .file_name()
             .and_then(|n| n.to_str())
             .unwrap_or("")
             .to_string();
-        let end_index = file_name.rfind('.').unwrap_or_else(|| file_name.len());
+        let end_index = file_name.rfind('.').unwrap_or(file_name.len());
         let file_name = file_name[0..end_index].to_string();
 
-        Self {
-            code: load_and_lex_code(path),
+        let (code, memory) = lex_code(words)?;
+
+        Ok(Self {
+            code,
+            memory,
+            exe: target.exe_name(&file_name),
             name: file_name,
-        }
+            target,
+            ret_stack: RET_STACK_SIZE,
+        })
     }
 }
 
@@ -41,51 +66,69 @@ impl Compiler {
         return Option::Some(index as u64);        
     }
 
-    fn get_op_type(&self, ptr: u64) -> Option<LOpType> {
-        let index = self.idx(ptr);
-        if let None = index {
-            return None;
+    pub fn op(&self, ptr: u64) -> Option<&LOp> {
+        match self.idx(ptr) {
+            Some(index) => self.code.get(index as usize),
+            None => None,
         }
+    }
 
-        let index = index.unwrap() as usize;
-        let value = self.code.get(index);
-        if let None = value {
+    fn get_op_type(&self, ptr: u64) -> Option<LOp> {
+        let index = self.idx(ptr)? as usize;
+        let value = self.code.get(index)?;
+
+        return Some(value.clone());
+    }
+
+    /*
+        The if or do right after the op at `ptr`, unless it is a jump target.
+     */
+    fn fused_branch(&self, ptr: u64, targets: &HashSet<u64>) -> Option<(&LOp, u64)> {
+        if targets.contains(&(ptr + 1)) {
             return None;
         }
 
-        return Some(value.unwrap().clone());
+        match self.op(ptr + 1) {
+            Some(op) => match op.op {
+                LOpType::If(block_ip) | LOpType::Do(block_ip) => Some((op, block_ip)),
+                _ => None,
+            },
+            None => None,
+        }
     }
 
-    fn compile_asm(&self, file: &mut AsmFile) -> bool {
+    fn compile_asm(&self, file: &mut AsmFile) -> Result<(), CompileError> {
         let mut ptr: u64 = 0;
         let csize = self.code.len() as u64;
         let mut strs: Vec<String> = Vec::new();
+        let targets = jump_targets(&self.code);
         while ptr < csize {
             let value = self.get_op_type(ptr);
-            if let None = value {
-                return true;
+            if value.is_none() {
+                return Ok(());
             }
 
             let value = value.unwrap();
 
             file.addr(ptr);
+            file.set_loc(Some(value.loc.clone()));
             
-            match value {
+            match value.op {
                 LOpType::Push(x) => {
                     match x {
                         LValue::Number(y) => {
                             file.title("push u64");
-                            file.code(format!("push {}", y as u64).as_str());
+                            file.push_imm(y);
                         },
                         LValue::Text(text) => {
-                            file.title(format!("push str lit {} \"{}\":{}", strs.len(), text.as_str(), text.len()).as_str());
+                            file.title(format!("push str lit {} {:?}:{}", strs.len(), text.as_str(), text.len()).as_str());
                             file.code(format!("lea rax, [rel str_{}]", strs.len()).as_str());
                             file.code("push rax");
                             file.code(format!("push {}", text.len()).as_str());
                             strs.push(text);
                         },
                         _ => {
-                            println!("Not implemented! Push {:?}", x);
+                            return Err(CompileError::UnknownWord(value.loc.clone(), format!("{:?}", x)));
                         }
                     }
                 },
@@ -167,65 +210,41 @@ impl Compiler {
                     file.code("xchg rax, [rsp]");
                     file.code("push rax");
                 },
-                LOpType::Greater => {
-                    file.title(">");
-                    file.code("pop rbx");
-                    file.code("pop rax");
-                    file.code("xor rcx, rcx");
-                    file.code("cmp rax, rbx");
-                    file.code("setg cl");
-                    file.code("movzx rcx, cl");
-                    file.code("push rcx");
-                },
-                LOpType::Less => {
-                    file.title("<");
-                    file.code("pop rbx");
-                    file.code("pop rax");
-                    file.code("xor rcx, rcx");
-                    file.code("cmp rax, rbx");
-                    file.code("setl cl");
-                    file.code("movzx rcx, cl");
-                    file.code("push rcx");
-                },
-                LOpType::GreaterEqual => {
-                    file.title(">=");
-                    file.code("pop rbx");
-                    file.code("pop rax");
-                    file.code("xor rcx, rcx");
-                    file.code("cmp rax, rbx");
-                    file.code("setge cl");
-                    file.code("movzx rcx, cl");
-                    file.code("push rcx");
-                },
-                LOpType::LessEqual => {
-                    file.title("<=");
-                    file.code("pop rbx");
-                    file.code("pop rax");
-                    file.code("xor rcx, rcx");
-                    file.code("cmp rax, rbx");
-                    file.code("setle cl");
-                    file.code("movzx rcx, cl");
-                    file.code("push rcx");
-                },
-                LOpType::Equal => {
-                    file.title("=");
-                    file.code("pop rbx");
-                    file.code("pop rax");
-                    file.code("xor rcx, rcx");
-                    file.code("cmp rax, rbx");
-                    file.code("sete cl");
-                    file.code("movzx rcx, cl");
-                    file.code("push rcx");
-                },
-                LOpType::NotEqual => {
-                    file.title("!=");
+                LOpType::Greater | LOpType::Less | LOpType::GreaterEqual | LOpType::LessEqual | LOpType::Equal | LOpType::NotEqual => {
+                    let (title, set, skip) = match value.op {
+                        LOpType::Greater => (">", "setg", "jle"),
+                        LOpType::Less => ("<", "setl", "jge"),
+                        LOpType::GreaterEqual => (">=", "setge", "jl"),
+                        LOpType::LessEqual => ("<=", "setle", "jg"),
+                        LOpType::Equal => ("=", "sete", "jne"),
+                        _ => ("!=", "setne", "je"),
+                    };
+
+                    file.title(title);
                     file.code("pop rbx");
                     file.code("pop rax");
-                    file.code("xor rcx, rcx");
-                    file.code("cmp rax, rbx");
-                    file.code("setne cl");
-                    file.code("movzx rcx, cl");
-                    file.code("push rcx");
+
+                    /*
+                        A comparison directly followed by an if or do jumps on
+                        the flags instead of pushing a 0/1 for the branch to
+                        pop again. Only done when nothing jumps to the branch,
+                        since a jump there would expect the value on the stack.
+                     */
+                    if let Some((branch, block_ip)) = self.fused_branch(ptr, &targets) {
+                        ptr += 1;
+                        file.addr(ptr);
+                        file.set_loc(Some(branch.loc.clone()));
+                        let kind = if let LOpType::If(_) = branch.op { "if" } else { "do" };
+                        file.title(format!("{} {}", title, kind).as_str());
+                        file.code("cmp rax, rbx");
+                        file.code(format!("{} addr_{}", skip, block_ip).as_str());
+                    } else {
+                        file.code("xor rcx, rcx");
+                        file.code("cmp rax, rbx");
+                        file.code(format!("{} cl", set).as_str());
+                        file.code("movzx rcx, cl");
+                        file.code("push rcx");
+                    }
                 },
                 LOpType::If(block_ip) => {
                     file.title("if");
@@ -246,6 +265,10 @@ impl Compiler {
                 LOpType::While => {
                     file.title("while");
                 },
+                LOpType::Jump(block_ip) => {
+                    file.title("jump");
+                    file.code(format!("jmp addr_{}", block_ip).as_str());
+                },
                 LOpType::End(block_ip) => {
                     file.title("end");
                     file.code(format!("jmp addr_{}", block_ip).as_str());
@@ -255,21 +278,54 @@ impl Compiler {
                     file.code("lea rax, [rel membuf]");
                     file.code("push rax");
                 },
-                LOpType::Load => {
-                    file.title("load");
-                    file.code("xor rcx, rcx");
+                LOpType::Argc => {
+                    file.title("argc");
+                    file.code("push qword [rel args_argc]");
+                },
+                LOpType::Argv => {
+                    file.title("argv");
+                    file.code("push qword [rel args_argv]");
+                },
+                LOpType::Envp => {
+                    file.title("envp");
+                    file.code("push qword [rel args_envp]");
+                },
+                LOpType::Region(name, offset) => {
+                    file.title(format!("memory {}", name).as_str());
+                    file.code(format!("lea rax, [rel membuf + {}]", offset).as_str());
+                    file.code("push rax");
+                },
+                LOpType::Load(bits, signed) => {
+                    let load = match (bits, signed) {
+                        (8, false) => "movzx rcx, byte [rax]",
+                        (8, true) => "movsx rcx, byte [rax]",
+                        (16, false) => "movzx rcx, word [rax]",
+                        (16, true) => "movsx rcx, word [rax]",
+                        (32, false) => "mov ecx, dword [rax]",
+                        (32, true) => "movsxd rcx, dword [rax]",
+                        _ => "mov rcx, qword [rax]",
+                    };
+
+                    file.title(format!("load {}{}", bits, if signed { " signed" } else { "" }).as_str());
                     file.code("pop rax");
-                    file.code("mov cl, [rax]");
+                    file.code(load);
                     file.code("push rcx");
                 },
-                LOpType::Store => {
+                LOpType::Store(bits) => {
                     /*
                         value address
                      */
-                    file.title("store");
+                    let store = match bits {
+                        8 => "mov [rax], cl",
+                        16 => "mov [rax], cx",
+                        32 => "mov [rax], ecx",
+                        _ => "mov [rax], rcx",
+                    };
+
+                    file.title(format!("store {}", bits).as_str());
                     file.code("pop rax");
                     file.code("pop rcx");
-                    file.code("mov [rax], cl");
+                    file.code(store);
                 },
                 LOpType::Puts(nl) => {
                     /*
@@ -293,9 +349,61 @@ impl Compiler {
                     }
                     file.code("add rsp, 16");
                 }
+                /*
+                    rsp is the data stack, so return addresses live on a
+                    separate stack and rsp is swapped around every call.
+                 */
+                LOpType::Proc(name, skip_ip) => {
+                    file.title(format!("proc {}", name).as_str());
+                    file.code(format!("jmp addr_{}", skip_ip).as_str());
+                    file.write(format!("proc_{}:\n", ptr).as_str());
+                    file.code("mov [rel ret_stack_rsp], rsp");
+                    file.code("mov rsp, rax");
+                },
+                LOpType::Call(name, proc_ip) => {
+                    file.title(format!("call {}", name).as_str());
+                    file.code("mov rax, rsp");
+                    file.code("mov rsp, [rel ret_stack_rsp]");
+                    file.code("lea rbx, [rel ret_stack + 8]");
+                    file.code("cmp rsp, rbx");
+                    file.code("jb ret_stack_overflow");
+                    file.code(format!("call proc_{}", proc_ip).as_str());
+                    file.code("mov [rel ret_stack_rsp], rsp");
+                    file.code("mov rsp, rax");
+                },
+                LOpType::Ret => {
+                    file.title("ret");
+                    file.code("mov rax, rsp");
+                    file.code("mov rsp, [rel ret_stack_rsp]");
+                    file.code("ret");
+                },
+                LOpType::Syscall(count) => {
+                    /*
+                        args... number, the number on top and the last
+                        argument right below it. printf output is flushed
+                        first so it stays in order with what the syscall writes.
+                     */
+                    if file.target() != Target::Linux {
+                        return Err(CompileError::Unsupported(value.loc.clone(), format!("syscall{}", count), file.target().name().to_string()));
+                    }
+
+                    let regs = ["rdi", "rsi", "rdx", "r10", "r8", "r9"];
+                    file.title(format!("syscall{}", count).as_str());
+                    file.code("call flush");
+                    file.code("pop rax");
+                    for reg in regs[0..count as usize].iter().rev() {
+                        file.code(format!("pop {}", reg).as_str());
+                    }
+                    file.code("syscall");
+                    file.code("push rax");
+                },
+                LOpType::Exit => {
+                    file.title("exit");
+                    file.code("pop rcx");
+                    file.code("jmp exit_program");
+                },
                 _ => {
-                    println!("Not implemented! {:?}", value);
-                    return false;
+                    return Err(CompileError::UnknownWord(value.loc.clone(), format!("{:?}", value.op)));
                 }
             }
 
@@ -303,31 +411,115 @@ impl Compiler {
         }
 
         file.addr(csize);
+        file.set_loc(None);
         file.write("segment .data\n");
 
         for (idx, text) in strs.iter().enumerate() {
-            file.title(format!("str lit {} \"{}\":{}", idx, text, text.len()).as_str());
+            file.title(format!("str lit {} {:?}:{}", idx, text, text.len()).as_str());
             
             let data = text.bytes().map(|x| x.to_string()).collect::<Vec<String>>().join(", ");
             file.write(format!("str_{}:\n    db {}\n", idx, data).as_str());
         }
 
-        return true;
+        return Ok(());
     }
 
-    pub fn compile(&self) -> bool {
-        let mut asmfile = pre_compile(self.name.as_str());
+    pub fn check(&self) -> Result<(), CompileError> {
+        check_stack(self)
+    }
 
-        if !self.compile_asm(&mut asmfile) {
-            println!("Failed to create ASM file!");
-            return false;
-        }
+    /*
+        The optimizer works in program order, so flip the code around it.
+     */
+    pub fn optimize(&mut self, level: u8) {
+        self.code.reverse();
+        optimize(&mut self.code, level);
+        self.code.reverse();
+    }
 
-        if !post_compile(asmfile) {
-            println!("Failed to compile Ktnack program!");
-            return false;
+    /*
+        The assembly for the program, without writing or assembling it.
+        The code is expected to have passed `check` before it was optimized.
+     */
+    pub fn asm(&self) -> Result<String, CompileError> {
+        let mut asmfile = pre_compile(self.target, self.memory.size, self.ret_stack);
+        self.compile_asm(&mut asmfile)?;
+
+        return Ok(end_asm(asmfile));
+    }
+
+    /*
+        Writes the assembly for the program, without assembling it, and
+        returns the name of the file written.
+     */
+    pub fn write_asm(&self) -> Result<String, CompileError> {
+        let text = self.asm()?;
+        let asm_name = format!("{}.asm", self.name);
+        if let Err(error) = fs::write(&asm_name, text) {
+            return Err(CompileError::Io(asm_name, error.to_string()));
         }
 
-        return true;
+        return Ok(asm_name);
     }
-}
+
+    /*
+        Writes the executable to `path` instead of next to where the compiler
+        runs. The intermediate files are named after it, minus its extension.
+     */
+    pub fn set_output(&mut self, path: &str) {
+        self.name = Path::new(path).with_extension("").to_string_lossy().to_string();
+        self.exe = path.to_string();
+    }
+
+    /*
+        How many calls deep the program may go, compiled or simulated.
+     */
+    pub fn set_ret_stack(&mut self, size: u64) {
+        self.ret_stack = size;
+    }
+
+    pub fn ret_stack(&self) -> u64 {
+        self.ret_stack
+    }
+
+    pub fn exe_name(&self) -> &str {
+        self.exe.as_str()
+    }
+
+    /*
+        Progress is reported on stderr, so that with `run` stdout only has
+        what the program itself prints.
+     */
+    pub fn compile(&self) -> Result<(), CompileError> {
+        eprintln!("Generating ASM from Ktnack code...");
+        self.write_asm()?;
+        post_compile(self.name.as_str(), self.exe.as_str(), self.target)?;
+
+        return Ok(());
+    }
+
+    pub fn clean(&self) {
+        remove_intermediates(self.name.as_str(), self.target);
+    }
+
+    /*
+        Runs the program in-process, returning its exit status or None if
+        the simulation itself failed. `args` is what the program sees as its
+        command line, starting with its own name.
+     */
+    pub fn simulate(&self, args: &[String]) -> Option<i32> {
+        let stdout = io::stdout();
+        let mut out = io::BufWriter::new(stdout.lock());
+
+        let mut sim = Simulator::new(self);
+        sim.set_args(args);
+        match sim.run(&mut out) {
+            Ok(code) => Some(code),
+            Err(error) => {
+                drop(out);
+                eprintln!("Simulation failed: {}", error);
+                None
+            }
+        }
+    }
+}
\ No newline at end of file
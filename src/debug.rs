@@ -0,0 +1,336 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use crate::ltypes::*;
+use crate::compile::Compiler;
+use crate::sim::{Simulator, MEM_ADDR};
+use crate::src::{load_code, find_macros};
+
+const HELP: &str = "\
+Commands:
+    s, step [n]         Run the next op, or the next n ops
+    c, continue         Run until a breakpoint or the end of the program
+    b, break <where>    Stop at a line, file:line or every use of a macro,
+                        without <where> lists the breakpoints
+    d, delete <n>       Remove breakpoint n
+    stack               Print the stack, top last
+    mem <at> [count]    Dump count bytes of memory from an offset into @ or
+                        from a named memory region
+    w, where            Show the op about to run
+    q, quit             Stop debugging
+An empty line repeats the last command.
+";
+
+/*
+    Where the debugger stops. A macro breakpoint matches every op that came
+    from the macro's body.
+ */
+enum Breakpoint {
+    Line(String, usize),
+    Macro(String, LLoc, LLoc),
+}
+
+impl Breakpoint {
+    fn matches(&self, loc: &LLoc) -> bool {
+        match self {
+            Breakpoint::Line(file, line) => loc.line == *line && (loc.file == *file || Path::new(&loc.file).ends_with(file)),
+            Breakpoint::Macro(_, start, end) => {
+                let inside = |x: &LLoc| {
+                    x.file == start.file && (x.line, x.col) > (start.line, start.col) && (x.line, x.col) < (end.line, end.col)
+                };
+
+                /* the op, or any macro use it was expanded from */
+                let mut at = Some(loc);
+                while let Some(x) = at {
+                    if inside(x) {
+                        return true;
+                    }
+                    at = x.from.as_deref();
+                }
+                return false;
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Breakpoint::Line(file, line) => write!(f, "{}:{}", file, line),
+            Breakpoint::Macro(name, start, _) => write!(f, "macro {} ({})", name, start),
+        }
+    }
+}
+
+struct Debugger<'a> {
+    sim: Simulator<'a>,
+    compiler: &'a Compiler,
+    file: String,
+    macros: HashMap<String, LMacro>,
+    breakpoints: Vec<Breakpoint>,
+    sources: HashMap<String, Vec<String>>,
+    done: bool,
+}
+
+impl<'a> Debugger<'a> {
+    fn source_line(&mut self, loc: &LLoc) -> Option<String> {
+        if !self.sources.contains_key(&loc.file) {
+            let lines = fs::read_to_string(&loc.file).map(|x| x.lines().map(|l| l.to_string()).collect()).unwrap_or_default();
+            self.sources.insert(loc.file.clone(), lines);
+        }
+
+        self.sources[&loc.file].get(loc.line.wrapping_sub(1)).cloned()
+    }
+
+    /*
+        The op about to run and the line it came from, with a marker under
+        the word, followed by the macro uses it was expanded from.
+     */
+    fn show(&mut self) {
+        let ip = self.sim.ip();
+        let op = match self.compiler.op(ip) {
+            Some(op) => op.clone(),
+            None => {
+                println!("at the end of the program");
+                return;
+            }
+        };
+
+        println!("ip {} at {}: {}", ip, op.loc, op.op);
+        if let Some(line) = self.source_line(&op.loc) {
+            println!("    {}", line);
+            println!("    {}^", " ".repeat(op.loc.col.saturating_sub(1)));
+        }
+
+        let mut site = op.loc.from.clone();
+        while let Some(loc) = site {
+            println!("  expanded from {}", loc);
+            site = loc.from.clone();
+        }
+    }
+
+    /*
+        Runs one op. Returns false once the program can't go on.
+     */
+    fn step(&mut self) -> bool {
+        if self.done {
+            println!("The program is not running.");
+            return false;
+        }
+
+        let mut out = io::stdout();
+        let result = self.sim.step(&mut out);
+        let _ = out.flush();
+
+        match result {
+            Ok(None) => return true,
+            Ok(Some(code)) => println!("Program exited with status {}.", code),
+            Err(error) => println!("Program stopped: {}", error),
+        }
+
+        self.done = true;
+        return false;
+    }
+
+    fn breakpoint_at(&self, ip: u64) -> Option<usize> {
+        let loc = &self.compiler.op(ip)?.loc;
+        self.breakpoints.iter().position(|x| x.matches(loc))
+    }
+
+    /*
+        Runs until an op matching a breakpoint is reached. Falling through
+        from an op of the same breakpoint doesn't count, so a breakpoint
+        stops once per visit rather than at every op on its line, but a
+        jump back into it is a new visit, like the next round of a loop
+        written on one line.
+     */
+    fn cont(&mut self) {
+        loop {
+            let from = self.sim.ip();
+            let before = self.breakpoint_at(from);
+            if !self.step() {
+                return;
+            }
+
+            let ip = self.sim.ip();
+            if let Some(index) = self.breakpoint_at(ip) {
+                if before != Some(index) || ip != from + 1 {
+                    println!("Breakpoint {}, {}", index + 1, self.breakpoints[index]);
+                    self.show();
+                    return;
+                }
+            }
+        }
+    }
+
+    fn add_breakpoint(&mut self, at: &str) {
+        let breakpoint = if let Ok(line) = at.parse::<usize>() {
+            Breakpoint::Line(self.file.clone(), line)
+        } else if let Some((file, Ok(line))) = at.rsplit_once(':').map(|(f, l)| (f, l.parse::<usize>())) {
+            Breakpoint::Line(file.to_string(), line)
+        } else if let Some(mcro) = self.macros.get(at) {
+            Breakpoint::Macro(mcro.name().clone(), mcro.loc().clone(), mcro.end().clone())
+        } else {
+            println!("No line or macro named `{}`.", at);
+            return;
+        };
+
+        let mut ip = 0;
+        let mut found = false;
+        while let Some(op) = self.compiler.op(ip) {
+            found |= breakpoint.matches(&op.loc);
+            ip += 1;
+        }
+
+        if !found {
+            println!("Nothing runs at {}, the breakpoint will never be hit.", breakpoint);
+        }
+
+        self.breakpoints.push(breakpoint);
+        println!("Breakpoint {} at {}", self.breakpoints.len(), self.breakpoints.last().unwrap());
+    }
+
+    fn dump(&mut self, at: &str, count: Option<&str>) {
+        let (offset, size) = match (at.parse::<u64>(), self.compiler.memory.get(at)) {
+            (Ok(offset), _) => (offset, 64),
+            (_, Some(region)) => (region.offset, region.size.min(256)),
+            _ => {
+                println!("No memory named `{}`.", at);
+                return;
+            }
+        };
+
+        let size = match count.map(|x| x.parse::<u64>()) {
+            Some(Ok(x)) => x,
+            Some(Err(_)) => {
+                println!("Invalid byte count `{}`.", count.unwrap());
+                return;
+            },
+            None => size,
+        };
+
+        let data = match self.sim.read(MEM_ADDR + offset, size as usize) {
+            Ok(data) => data,
+            Err(_) => {
+                println!("{} bytes at offset {} are outside of memory.", size, offset);
+                return;
+            }
+        };
+
+        for (row, chunk) in data.chunks(16).enumerate() {
+            let hex: Vec<String> = chunk.iter().map(|x| format!("{:02x}", x)).collect();
+            let text: String = chunk.iter().map(|x| if x.is_ascii_graphic() || *x == b' ' { *x as char } else { '.' }).collect();
+            println!("{:8}  {:<48} {}", offset + row as u64 * 16, hex.join(" "), text);
+        }
+    }
+
+    /*
+        Handles one command. Returns false to stop debugging.
+     */
+    fn command(&mut self, line: &str) -> bool {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["s" | "step"] => {
+                if self.step() {
+                    self.show();
+                }
+            },
+            ["s" | "step", n] => match n.parse::<usize>() {
+                Ok(n) => {
+                    let mut running = true;
+                    for _ in 0..n {
+                        running = self.step();
+                        if !running {
+                            break;
+                        }
+                    }
+                    if running {
+                        self.show();
+                    }
+                },
+                Err(_) => println!("Invalid step count `{}`.", n),
+            },
+            ["c" | "continue"] => self.cont(),
+            ["b" | "break"] => {
+                if self.breakpoints.is_empty() {
+                    println!("No breakpoints.");
+                }
+                for (i, breakpoint) in self.breakpoints.iter().enumerate() {
+                    println!("{}: {}", i + 1, breakpoint);
+                }
+            },
+            ["b" | "break", at] => self.add_breakpoint(at),
+            ["d" | "delete", n] => match n.parse::<usize>() {
+                Ok(n) if n >= 1 && n <= self.breakpoints.len() => {
+                    let breakpoint = self.breakpoints.remove(n - 1);
+                    println!("Deleted breakpoint {}, {}", n, breakpoint);
+                },
+                _ => println!("No breakpoint {}.", n),
+            },
+            ["stack"] => {
+                let values: Vec<String> = self.sim.stack().iter().map(|x| (*x as i64).to_string()).collect();
+                println!("[{}]", values.join(", "));
+            },
+            ["mem", at] => self.dump(at, None),
+            ["mem", at, count] => self.dump(at, Some(count)),
+            ["w" | "where"] => self.show(),
+            ["h" | "help"] => print!("{}", HELP),
+            ["q" | "quit"] => return false,
+            _ => println!("Unknown command `{}`, type `help` for a list.", line.trim()),
+        }
+
+        return true;
+    }
+}
+
+/*
+    Runs a program op by op in the simulator, taking commands from stdin.
+ */
+pub fn run_debugger(file_name: &str, compiler: &Compiler, include_dirs: &[String], args: &[String]) -> bool {
+    let macros = load_code(file_name, include_dirs).and_then(|x| find_macros(&x)).unwrap_or_default();
+
+    let mut sim = Simulator::new(compiler);
+    sim.set_args(args);
+
+    let mut debugger = Debugger {
+        sim,
+        compiler,
+        file: file_name.to_string(),
+        macros,
+        breakpoints: Vec::new(),
+        sources: HashMap::new(),
+        done: false,
+    };
+
+    println!("Debugging {}, type `help` for a list of commands.", file_name);
+    debugger.show();
+
+    let stdin = io::stdin();
+    let mut last = String::new();
+    loop {
+        print!("(debug) ");
+        let _ = io::stdout().flush();
+
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => {
+                println!();
+                return true;
+            },
+            Ok(_) => {},
+        }
+
+        if line.trim().is_empty() {
+            line = last.clone();
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        if !debugger.command(&line) {
+            return true;
+        }
+        last = line;
+    }
+}
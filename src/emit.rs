@@ -0,0 +1,100 @@
+use crate::ltypes::*;
+use crate::json::Json;
+use crate::compile::Compiler;
+
+/*
+    The stages of the compiler that --emit can print instead of building.
+ */
+#[derive(Clone, Copy, PartialEq)]
+pub enum Emit {
+    Tokens,
+    Expanded,
+    Ops,
+    Asm,
+}
+
+impl Emit {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "tokens" => Some(Emit::Tokens),
+            "expanded" => Some(Emit::Expanded),
+            "ops" => Some(Emit::Ops),
+            "asm" => Some(Emit::Asm),
+            _ => None,
+        }
+    }
+}
+
+fn with_loc(mut fields: Vec<(&str, Json)>, loc: &LLoc) -> Json {
+    fields.push(("file", Json::Str(loc.file.clone())));
+    fields.push(("line", Json::Number(loc.line as i64)));
+    fields.push(("col", Json::Number(loc.col as i64)));
+    Json::object(fields)
+}
+
+fn lines(items: Vec<String>) -> String {
+    items.iter().map(|x| format!("{}\n", x)).collect()
+}
+
+pub fn emit_tokens(tokens: &[LToken], json: bool) -> String {
+    if json {
+        let items = tokens.iter().map(|x| with_loc(vec![("text", Json::Str(x.text.clone()))], &x.loc)).collect();
+        return format!("{}\n", Json::Array(items));
+    }
+
+    lines(tokens.iter().map(|x| format!("{}\t{}", x.loc, x.text)).collect())
+}
+
+fn word_value(value: &LValueType) -> (&'static str, Json) {
+    match value {
+        LValueType::Number(x) => ("number", Json::Number(*x)),
+        LValueType::Char(x) => ("char", Json::Number(*x)),
+        LValueType::Text(x) => ("text", Json::Str(x.clone())),
+        LValueType::Symbol(x) => ("symbol", Json::Str(x.clone())),
+        LValueType::None => ("none", Json::Null),
+    }
+}
+
+pub fn emit_words(words: &[LWord], json: bool) -> String {
+    if json {
+        let items = words.iter().map(|x| {
+            let (kind, value) = word_value(&x.value);
+            with_loc(vec![("kind", Json::Str(kind.to_string())), ("value", value)], &x.loc)
+        }).collect();
+        return format!("{}\n", Json::Array(items));
+    }
+
+    lines(words.iter().map(|x| format!("{}\t{}", x.loc, x.value.text())).collect())
+}
+
+/*
+    Ops in program order, as they reach codegen, with their jump targets.
+ */
+pub fn emit_ops(compiler: &Compiler, json: bool) -> String {
+    let mut ops: Vec<(u64, &LOp)> = Vec::new();
+    while let Some(op) = compiler.op(ops.len() as u64) {
+        ops.push((ops.len() as u64, op));
+    }
+
+    if json {
+        let items = ops.iter().map(|(ip, op)| {
+            let target = match op.op.target() {
+                Some(x) => Json::Number(x as i64),
+                None => Json::Null,
+            };
+            with_loc(vec![("ip", Json::Number(*ip as i64)), ("op", Json::Str(format!("{}", op.op))), ("target", target)], &op.loc)
+        }).collect();
+        return format!("{}\n", Json::Array(items));
+    }
+
+    lines(ops.iter().map(|(ip, op)| format!("{}\t{}\t{}", ip, op.loc, op.op)).collect())
+}
+
+pub fn emit_asm(file_name: &str, text: &str, json: bool) -> String {
+    if json {
+        let object = Json::object(vec![("file", Json::Str(file_name.to_string())), ("asm", Json::Str(text.to_string()))]);
+        return format!("{}\n", object);
+    }
+
+    text.to_string()
+}
@@ -0,0 +1,134 @@
+use crate::ltypes::{LLoc, MEM_LIMIT};
+
+pub enum CompileError {
+    Io(String, String),
+    UnterminatedString(LLoc),
+    UnterminatedComment(LLoc),
+    InvalidCharLiteral(LLoc, String),
+    UnknownWord(LLoc, String),
+    UnbalancedBlock(LLoc, String),
+    MissingInclude(LLoc, String),
+    IncludeCycle(LLoc, String),
+    MissingMacroName(LLoc),
+    MissingMacroArgs(LLoc, String, usize),
+    MacroCycle(LLoc, String),
+    MissingProcName(LLoc),
+    MissingMemoryName(LLoc),
+    MissingConstName(LLoc),
+    StackUnderflow(LLoc, String, usize, usize),
+    BranchDepthMismatch(LLoc, i64, i64),
+    LoopDepthChange(LLoc, i64, i64),
+    StackNotEmpty(LLoc, usize),
+    UnknownStackEffect(LLoc, String),
+    Unsupported(LLoc, String, String),
+    DuplicateDefinition(LLoc, String),
+    ReservedName(LLoc, String),
+    InvalidMemorySize(LLoc, String),
+    MemoryTooLarge(LLoc, String),
+    InvalidConstExpr(LLoc, String),
+    AssemblerFailure(String),
+    LinkerFailure(String),
+}
+
+impl CompileError {
+    pub fn loc(&self) -> Option<&LLoc> {
+        match self {
+            CompileError::UnterminatedString(loc) => Some(loc),
+            CompileError::UnterminatedComment(loc) => Some(loc),
+            CompileError::InvalidCharLiteral(loc, _) => Some(loc),
+            CompileError::UnknownWord(loc, _) => Some(loc),
+            CompileError::UnbalancedBlock(loc, _) => Some(loc),
+            CompileError::MissingInclude(loc, _) => Some(loc),
+            CompileError::IncludeCycle(loc, _) => Some(loc),
+            CompileError::MissingMacroName(loc) => Some(loc),
+            CompileError::MissingMacroArgs(loc, _, _) => Some(loc),
+            CompileError::MacroCycle(loc, _) => Some(loc),
+            CompileError::MissingProcName(loc) => Some(loc),
+            CompileError::MissingMemoryName(loc) => Some(loc),
+            CompileError::MissingConstName(loc) => Some(loc),
+            CompileError::StackUnderflow(loc, _, _, _) => Some(loc),
+            CompileError::BranchDepthMismatch(loc, _, _) => Some(loc),
+            CompileError::LoopDepthChange(loc, _, _) => Some(loc),
+            CompileError::StackNotEmpty(loc, _) => Some(loc),
+            CompileError::UnknownStackEffect(loc, _) => Some(loc),
+            CompileError::Unsupported(loc, _, _) => Some(loc),
+            CompileError::DuplicateDefinition(loc, _) => Some(loc),
+            CompileError::ReservedName(loc, _) => Some(loc),
+            CompileError::InvalidMemorySize(loc, _) => Some(loc),
+            CompileError::MemoryTooLarge(loc, _) => Some(loc),
+            CompileError::InvalidConstExpr(loc, _) => Some(loc),
+            CompileError::Io(_, _) | CompileError::AssemblerFailure(_) | CompileError::LinkerFailure(_) => None,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            CompileError::Io(path, error) => format!("could not read `{}`: {}", path, error),
+            CompileError::UnterminatedString(_) => "unterminated string literal".to_string(),
+            CompileError::UnterminatedComment(_) => "unterminated block comment".to_string(),
+            CompileError::InvalidCharLiteral(_, text) => format!("character literal {} must contain exactly one character", text),
+            CompileError::UnknownWord(_, word) => format!("unknown word `{}`", word),
+            CompileError::UnbalancedBlock(_, text) => text.clone(),
+            CompileError::MissingInclude(_, name) => {
+                if name.is_empty() {
+                    "missing file name after `inc`".to_string()
+                } else {
+                    format!("included file `{}` not found", name)
+                }
+            },
+            CompileError::IncludeCycle(_, chain) => format!("include cycle: {}", chain),
+            CompileError::MissingMacroName(_) => "missing macro name after `macro`".to_string(),
+            CompileError::MissingMacroArgs(_, name, count) => format!("macro `{}` takes {} argument(s)", name, count),
+            CompileError::MacroCycle(_, chain) => format!("macro expands into itself: {}", chain),
+            CompileError::MissingProcName(_) => "missing procedure name after `proc`".to_string(),
+            CompileError::MissingMemoryName(_) => "missing memory name after `memory`".to_string(),
+            CompileError::MissingConstName(_) => "missing constant name after `const`".to_string(),
+            CompileError::StackUnderflow(_, word, needed, depth) => {
+                format!("stack underflow: `{}` needs {} value(s) but the stack only has {}", word, needed, depth)
+            },
+            CompileError::BranchDepthMismatch(_, expected, found) => {
+                format!("branches leave different stack depths ({} and {})", expected, found)
+            },
+            CompileError::LoopDepthChange(_, before, after) => {
+                format!("loop body changes the stack depth from {} to {}", before, after)
+            },
+            CompileError::StackNotEmpty(_, depth) => format!("{} value(s) left on the stack at the end of the program", depth),
+            CompileError::UnknownStackEffect(_, name) => {
+                format!("procedure `{}` does not have a consistent stack effect", name)
+            },
+            CompileError::Unsupported(_, what, target) => format!("{} is not supported on the {} target", what, target),
+            CompileError::DuplicateDefinition(_, name) => format!("`{}` is already defined", name),
+            CompileError::ReservedName(_, name) => format!("`{}` is a built-in word and can't be used as a name", name),
+            CompileError::InvalidMemorySize(_, name) => format!("memory `{}` needs a non-negative size", name),
+            CompileError::MemoryTooLarge(_, name) => format!("memory `{}` makes the memory buffer larger than {} bytes", name, MEM_LIMIT),
+            CompileError::InvalidConstExpr(_, text) => format!("invalid constant expression: {}", text),
+            CompileError::AssemblerFailure(text) => format!("assembler failed: {}", text),
+            CompileError::LinkerFailure(text) => format!("linker failed: {}", text),
+        }
+    }
+}
+
+impl std::fmt::Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let loc = match self.loc() {
+            Some(loc) => loc,
+            None => return write!(f, "error: {}", self.message()),
+        };
+
+        write!(f, "{}: error: {}", loc, self.message())?;
+
+        /* the macro uses the word was expanded from, innermost first */
+        let mut site = loc.from.as_deref();
+        while let Some(loc) = site {
+            write!(f, "\n  expanded from {}", loc)?;
+            site = loc.from.as_deref();
+        }
+        return Ok(());
+    }
+}
+
+impl std::fmt::Debug for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
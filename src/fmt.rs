@@ -0,0 +1,115 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::ltypes::*;
+use crate::error::CompileError;
+use crate::src::{get_source_lines, opens_block};
+use crate::golden::{find_sources, diff};
+
+const INDENT: &str = "    ";
+
+/*
+    Reformats source the way it is written by hand: lines stay where they
+    are, words are separated by a single space and every line is indented
+    one level for each block it is inside of. A line starting with `else`,
+    `do` or `end` lines up with the word that opened its block. Comments
+    and literals are kept as written.
+ */
+pub fn format_source(text: &str, file: &str) -> Result<String, CompileError> {
+    let lines = get_source_lines(text, file)?;
+    let mut depth: usize = 0;
+    let mut result = String::new();
+
+    for (index, pieces) in lines.iter().enumerate() {
+        if index > 0 {
+            result.push('\n');
+        }
+
+        let first = match pieces.first() {
+            Some(LPiece::Word(word)) => word.as_str(),
+            Some(LPiece::Comment(_)) => "",
+            None => continue,
+        };
+
+        let indent = if matches!(first, "else" | "do" | "end") { depth.saturating_sub(1) } else { depth };
+        result.push_str(INDENT.repeat(indent).as_str());
+
+        let texts: Vec<&str> = pieces.iter().map(|x| match x {
+            LPiece::Word(word) => word.as_str(),
+            LPiece::Comment(comment) => comment.as_str(),
+        }).collect();
+        result.push_str(texts.join(" ").as_str());
+
+        for piece in pieces.iter() {
+            if let LPiece::Word(word) = piece {
+                if opens_block(word) {
+                    depth += 1;
+                } else if word == "end" {
+                    depth = depth.saturating_sub(1);
+                }
+            }
+        }
+    }
+
+    if text.ends_with('\n') {
+        result.push('\n');
+    }
+
+    return Ok(result);
+}
+
+/*
+    Formats a file, or every `.ktnck` file under a directory, in place.
+    With `check` nothing is written, and files that would change are listed
+    with a diff instead. Returns whether everything went fine, and when
+    checking, whether every file was already formatted.
+ */
+pub fn run_fmt(path: &str, check: bool) -> bool {
+    let mut sources: Vec<PathBuf> = Vec::new();
+    if Path::new(path).is_dir() {
+        find_sources(Path::new(path), &mut sources);
+    } else if Path::new(path).is_file() {
+        sources.push(PathBuf::from(path));
+    } else {
+        eprintln!("Ktnack file not found: {}", path);
+        return false;
+    }
+
+    let mut ok = true;
+    for source in sources.iter() {
+        let name = source.to_string_lossy().to_string();
+        let text = match fs::read_to_string(source) {
+            Ok(text) => text,
+            Err(error) => {
+                eprintln!("{}", CompileError::Io(name, error.to_string()));
+                ok = false;
+                continue;
+            }
+        };
+
+        let formatted = match format_source(&text, &name) {
+            Ok(formatted) => formatted,
+            Err(error) => {
+                eprintln!("{}", error);
+                ok = false;
+                continue;
+            }
+        };
+
+        if formatted == text {
+            continue;
+        }
+
+        if check {
+            println!("{} is not formatted", name);
+            print!("{}", diff(&text, &formatted));
+            ok = false;
+        } else if let Err(error) = fs::write(source, &formatted) {
+            eprintln!("Could not write {}: {}", name, error);
+            ok = false;
+        } else {
+            println!("Formatted {}", name);
+        }
+    }
+
+    return ok;
+}
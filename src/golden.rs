@@ -0,0 +1,252 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use crate::asm::Target;
+
+/*
+    What a program is expected to do, kept in a `.expect` file next to its
+    source: the exit status on the first line, then everything it prints.
+
+        :exit 0
+        :stdout
+        ...
+
+    A program the compiler rejects instead has the errors it reports, with
+    the path of the source shortened to its file name.
+
+        :error
+        ...
+ */
+#[derive(Clone, PartialEq)]
+struct Outcome {
+    exit: i32,
+    stdout: String,
+    error: Option<String>,
+}
+
+impl Outcome {
+    fn rejected(error: String) -> Self {
+        Self {
+            exit: 1,
+            stdout: String::new(),
+            error: Some(error),
+        }
+    }
+
+    fn parse(text: &str) -> Option<Self> {
+        if let Some(error) = text.strip_prefix(":error\n") {
+            return Some(Outcome::rejected(error.to_string()));
+        }
+
+        let rest = text.strip_prefix(":exit ")?;
+        let (exit, rest) = rest.split_once('\n')?;
+        let stdout = rest.strip_prefix(":stdout\n")?;
+
+        Some(Self {
+            exit: exit.trim().parse().ok()?,
+            stdout: stdout.to_string(),
+            error: None,
+        })
+    }
+
+    fn to_text(&self) -> String {
+        match &self.error {
+            Some(error) => format!(":error\n{}", error),
+            None => format!(":exit {}\n:stdout\n{}", self.exit, self.stdout),
+        }
+    }
+}
+
+/*
+    How the test runner builds and runs each program. Programs are run by a
+    separate ktnack process, so one that exits or crashes doesn't take the
+    runner with it.
+ */
+pub struct TestOptions {
+    pub native: bool,
+    pub record: bool,
+    pub target: Target,
+    pub opt_level: u8,
+    pub include_dirs: Vec<String>,
+}
+
+impl TestOptions {
+    fn compiler_args(&self) -> Vec<String> {
+        let mut args = vec![format!("-O{}", self.opt_level), "--target".to_string(), self.target.name().to_string()];
+        for dir in self.include_dirs.iter() {
+            args.push("-I".to_string());
+            args.push(dir.clone());
+        }
+        return args;
+    }
+}
+
+pub fn find_sources(dir: &Path, found: &mut Vec<PathBuf>) {
+    let mut entries: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|x| x.ok()).map(|x| x.path()).collect(),
+        Err(_) => return,
+    };
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            find_sources(&path, found);
+        } else if path.extension().is_some_and(|x| x == "ktnck") {
+            found.push(path);
+        }
+    }
+}
+
+fn ktnack(args: &[String]) -> Result<std::process::Output, String> {
+    let exe = env::current_exe().map_err(|e| e.to_string())?;
+    Command::new(exe).args(args).stdin(Stdio::null()).output().map_err(|e| e.to_string())
+}
+
+fn run_source(source: &Path, options: &TestOptions) -> Result<Outcome, String> {
+    let source_name = source.to_string_lossy().to_string();
+    let mut args = options.compiler_args();
+
+    let mut check_args = args.clone();
+    check_args.extend(["check".to_string(), source_name.clone()]);
+    let check = ktnack(&check_args)?;
+    if !check.status.success() {
+        let file_name = source.file_name().map(|x| x.to_string_lossy().to_string()).unwrap_or_default();
+        let error = String::from_utf8_lossy(&check.stderr).replace(&source_name, &file_name);
+        return Ok(Outcome::rejected(error));
+    }
+
+    let output = if options.native {
+        let exe = source.with_extension("test");
+        let exe_name = options.target.exe_name(&exe.to_string_lossy());
+
+        args.extend(["com".to_string(), "--clean".to_string(), "-o".to_string(), exe_name.clone(), source_name]);
+        let build = ktnack(&args)?;
+        if !build.status.success() {
+            return Err(format!("could not compile:\n{}", String::from_utf8_lossy(&build.stderr)));
+        }
+
+        let path = Path::new(&exe_name);
+        let path = if path.components().count() == 1 { Path::new(".").join(path) } else { path.to_path_buf() };
+        let output = Command::new(&path).stdin(Stdio::null()).output().map_err(|e| e.to_string());
+        let _ = fs::remove_file(&exe_name);
+        output?
+    } else {
+        args.extend(["sim".to_string(), source_name]);
+        ktnack(&args)?
+    };
+
+    Ok(Outcome {
+        exit: output.status.code().unwrap_or(-1),
+        stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+        error: None,
+    })
+}
+
+/*
+    Line diff of the expected and actual output, from the longest common
+    subsequence of their lines.
+ */
+pub fn diff(expected: &str, actual: &str) -> String {
+    let a: Vec<&str> = expected.lines().collect();
+    let b: Vec<&str> = actual.lines().collect();
+
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] { lcs[i + 1][j + 1] + 1 } else { lcs[i + 1][j].max(lcs[i][j + 1]) };
+        }
+    }
+
+    let mut text = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            text.push_str(format!("     {}\n", a[i]).as_str());
+            i += 1;
+            j += 1;
+        } else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            text.push_str(format!("    -{}\n", a[i]).as_str());
+            i += 1;
+        } else {
+            text.push_str(format!("    +{}\n", b[j]).as_str());
+            j += 1;
+        }
+    }
+
+    return text;
+}
+
+/*
+    Runs every `.ktnck` file under `dir` and compares it with its `.expect`
+    file, or writes the `.expect` files when recording. Returns whether
+    every test passed.
+ */
+pub fn run_tests(dir: &str, options: &TestOptions) -> bool {
+    let mut sources: Vec<PathBuf> = Vec::new();
+    find_sources(Path::new(dir), &mut sources);
+    if sources.is_empty() {
+        eprintln!("No Ktnack files found in {}", dir);
+        return false;
+    }
+
+    let mut failed = 0;
+    for source in sources.iter() {
+        let expect_path = source.with_extension("expect");
+        let actual = match run_source(source, options) {
+            Ok(outcome) => outcome,
+            Err(error) => {
+                println!("FAIL {}: {}", source.display(), error);
+                failed += 1;
+                continue;
+            }
+        };
+
+        if options.record {
+            if let Err(error) = fs::write(&expect_path, actual.to_text()) {
+                println!("FAIL {}: could not write {}: {}", source.display(), expect_path.display(), error);
+                failed += 1;
+                continue;
+            }
+            println!("RECORD {}", expect_path.display());
+            continue;
+        }
+
+        let expected = match fs::read_to_string(&expect_path).ok().and_then(|x| Outcome::parse(&x)) {
+            Some(outcome) => outcome,
+            None => {
+                println!("FAIL {}: no valid {}, run with --record to create it", source.display(), expect_path.display());
+                failed += 1;
+                continue;
+            }
+        };
+
+        if expected == actual {
+            println!("PASS {}", source.display());
+            continue;
+        }
+
+        failed += 1;
+        println!("FAIL {}", source.display());
+        match (&expected.error, &actual.error) {
+            (Some(_), None) => println!("    expected the compiler to reject it"),
+            (None, Some(error)) => print!("    the compiler rejected it:\n{}", error),
+            (Some(expected_error), Some(actual_error)) => print!("{}", diff(expected_error, actual_error)),
+            (None, None) => {
+                if expected.exit != actual.exit {
+                    println!("    exit status: expected {}, got {}", expected.exit, actual.exit);
+                }
+                if expected.stdout != actual.stdout {
+                    print!("{}", diff(&expected.stdout, &actual.stdout));
+                }
+            },
+        }
+    }
+
+    if options.record {
+        return failed == 0;
+    }
+
+    println!("{} passed, {} failed", sources.len() - failed, failed);
+    return failed == 0;
+}
@@ -0,0 +1,466 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, ErrorKind, Read, Write};
+use crate::ltypes::*;
+use crate::json::Json;
+use crate::error::CompileError;
+use crate::compile::Compiler;
+use crate::asm::Target;
+use crate::src::{load_code_text, find_macros, expand_code, INTRINSICS};
+
+/*
+    A language server over stdin and stdout. Documents are kept as sent by
+    the editor, and every change runs them through the same steps as the
+    compiler. Positions are counted in chars, like the lexer does, which
+    matches what editors send for ASCII text.
+ */
+struct Document {
+    path: String,
+    text: String,
+    macros: HashMap<String, LMacro>,
+}
+
+struct Server {
+    documents: HashMap<String, Document>,
+    include_dirs: Vec<String>,
+    target: Target,
+    shutdown: bool,
+}
+
+/*
+    Reads the next message, Ok(None) once the input has ended. A message
+    that can't be understood is an error, but it has been read in full and
+    the next one can still be.
+ */
+fn read_message(input: &mut dyn BufRead) -> Result<Option<Json>, String> {
+    let mut length: Result<usize, String> = Err("message without a Content-Length header".to_string());
+    loop {
+        let mut line = String::new();
+        match input.read_line(&mut line) {
+            Ok(0) => return Ok(None),
+            Ok(_) => {},
+            Err(error) if error.kind() == ErrorKind::InvalidData => {
+                length = Err("header is not valid UTF-8".to_string());
+                continue;
+            },
+            Err(_) => return Ok(None),
+        }
+
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse().map_err(|_| format!("invalid Content-Length: {}", value.trim()));
+            }
+        }
+    }
+
+    let mut body = vec![0u8; length?];
+    if input.read_exact(&mut body).is_err() {
+        return Ok(None);
+    }
+
+    match Json::parse(&String::from_utf8_lossy(&body)) {
+        Ok(message) => Ok(Some(message)),
+        Err(error) => Err(format!("invalid JSON in message body: {}", error)),
+    }
+}
+
+fn write_message(message: &Json) {
+    let body = message.to_string();
+    let mut out = io::stdout().lock();
+    let _ = write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+    let _ = out.flush();
+}
+
+fn publish(uri: &str, diagnostics: Vec<Json>) {
+    write_message(&Json::object(vec![
+        ("jsonrpc", Json::Str("2.0".to_string())),
+        ("method", Json::Str("textDocument/publishDiagnostics".to_string())),
+        ("params", Json::object(vec![
+            ("uri", Json::Str(uri.to_string())),
+            ("diagnostics", Json::Array(diagnostics)),
+        ])),
+    ]));
+}
+
+fn hex_value(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|x| x as u8)
+}
+
+fn uri_to_path(uri: &str) -> String {
+    let text = uri.strip_prefix("file://").unwrap_or(uri);
+    let bytes = text.as_bytes();
+
+    let mut path: Vec<u8> = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let (Some(hi), Some(lo)) = (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                path.push(hi * 16 + lo);
+                i += 3;
+                continue;
+            }
+        }
+        path.push(bytes[i]);
+        i += 1;
+    }
+
+    let path = String::from_utf8_lossy(&path).to_string();
+
+    /* file:///C:/dir on Windows */
+    if path.len() > 2 && path.starts_with('/') && path.as_bytes()[2] == b':' {
+        return path[1..].to_string();
+    }
+    return path;
+}
+
+fn path_to_uri(path: &str) -> String {
+    let path = fs::canonicalize(path).map(|x| x.to_string_lossy().to_string()).unwrap_or_else(|_| path.to_string());
+    let path = path.replace('\\', "/");
+
+    let mut uri = String::from("file://");
+    if !path.starts_with('/') {
+        uri.push('/');
+    }
+
+    for b in path.bytes() {
+        if b.is_ascii_alphanumeric() || b"/-_.~:".contains(&b) {
+            uri.push(b as char);
+        } else {
+            uri.push_str(format!("%{:02X}", b).as_str());
+        }
+    }
+    return uri;
+}
+
+fn position(line: usize, character: usize) -> Json {
+    Json::object(vec![("line", Json::Number(line as i64)), ("character", Json::Number(character as i64))])
+}
+
+/*
+    The range of the word starting at a 1-based source location.
+ */
+fn word_range(text: &str, line: usize, col: usize) -> Json {
+    let chars: Vec<char> = text.lines().nth(line.saturating_sub(1)).unwrap_or("").chars().collect();
+    let start = col.saturating_sub(1);
+    let mut end = start;
+    while end < chars.len() && !chars[end].is_whitespace() {
+        end += 1;
+    }
+
+    let end = if end == start { start + 1 } else { end };
+    Json::object(vec![
+        ("start", position(line.saturating_sub(1), start)),
+        ("end", position(line.saturating_sub(1), end)),
+    ])
+}
+
+/*
+    The word under a 0-based position, split on whitespace like the lexer.
+ */
+fn word_at(text: &str, line: usize, character: usize) -> Option<String> {
+    let chars: Vec<char> = text.lines().nth(line)?.chars().collect();
+    if chars.is_empty() {
+        return None;
+    }
+
+    let mut start = character.min(chars.len());
+    while start > 0 && !chars[start - 1].is_whitespace() {
+        start -= 1;
+    }
+
+    let mut end = character.min(chars.len());
+    while end < chars.len() && !chars[end].is_whitespace() {
+        end += 1;
+    }
+
+    if start == end {
+        return None;
+    }
+    return Some(chars[start..end].iter().collect());
+}
+
+/*
+    Every step the compiler takes before codegen, stopping at the first
+    error.
+ */
+fn compile_errors(path: &str, text: &str, include_dirs: &[String], target: Target) -> Result<(), CompileError> {
+    let tokens = load_code_text(path, text, include_dirs)?;
+    let words = expand_code(&tokens)?;
+    let compiler = Compiler::from_words(path, target, words)?;
+    return compiler.check();
+}
+
+fn document_macros(path: &str, text: &str, include_dirs: &[String]) -> Result<HashMap<String, LMacro>, CompileError> {
+    let tokens = load_code_text(path, text, include_dirs)?;
+    return find_macros(&tokens);
+}
+
+impl Server {
+    fn new(include_dirs: &[String], target: Target) -> Self {
+        Self {
+            documents: HashMap::new(),
+            include_dirs: include_dirs.to_vec(),
+            target,
+            shutdown: false,
+        }
+    }
+
+    /*
+        Re-reads a document after it changed. The macros of the last version
+        that could be read are kept, so definitions still work while the
+        code is half typed.
+     */
+    fn update(&mut self, uri: &str, text: String) {
+        let path = uri_to_path(uri);
+        let macros = match document_macros(&path, &text, &self.include_dirs) {
+            Ok(macros) => macros,
+            Err(_) => match self.documents.remove(uri) {
+                Some(document) => document.macros,
+                None => HashMap::new(),
+            },
+        };
+
+        let document = Document { path, text, macros };
+        self.publish_diagnostics(uri, &document);
+        self.documents.insert(uri.to_string(), document);
+    }
+
+    /*
+        An error in an included file is shown at the macro use in the
+        document it was expanded from, or at the top of the document if
+        there is none, with its own location in the message.
+     */
+    fn publish_diagnostics(&self, uri: &str, document: &Document) {
+        let mut diagnostics: Vec<Json> = Vec::new();
+        if let Err(error) = compile_errors(&document.path, &document.text, &self.include_dirs, self.target) {
+            let (range, message) = match error.loc() {
+                Some(loc) if loc.file == document.path => (word_range(&document.text, loc.line, loc.col), error.message()),
+                Some(loc) => {
+                    let mut site = loc.from.as_deref();
+                    while site.is_some_and(|x| x.file != document.path) {
+                        site = site.and_then(|x| x.from.as_deref());
+                    }
+
+                    let range = match site {
+                        Some(site) => word_range(&document.text, site.line, site.col),
+                        None => word_range("", 1, 1),
+                    };
+                    (range, format!("{}: {}", loc, error.message()))
+                },
+                None => (word_range("", 1, 1), error.message()),
+            };
+
+            diagnostics.push(Json::object(vec![
+                ("range", range),
+                ("severity", Json::Number(1)),
+                ("source", Json::Str("ktnack".to_string())),
+                ("message", Json::Str(message)),
+            ]));
+        }
+
+        publish(uri, diagnostics);
+    }
+
+    fn macro_at(&self, params: &Json) -> Option<&LMacro> {
+        let uri = params.get("textDocument")?.get("uri")?.as_str()?;
+        let document = self.documents.get(uri)?;
+        let line = params.get("position")?.get("line")?.as_i64()? as usize;
+        let character = params.get("position")?.get("character")?.as_i64()? as usize;
+
+        let word = word_at(&document.text, line, character)?;
+        return document.macros.get(&word);
+    }
+
+    /*
+        The source of a macro's definition, from the open document when it
+        is one, otherwise from disk.
+     */
+    fn macro_source(&self, mcro: &LMacro) -> Option<String> {
+        let text = match self.documents.values().find(|x| x.path == mcro.loc().file) {
+            Some(document) => document.text.clone(),
+            None => fs::read_to_string(&mcro.loc().file).ok()?,
+        };
+
+        let lines: Vec<&str> = text.lines().collect();
+        let start = mcro.loc().line.saturating_sub(1);
+        let end = mcro.end().line.min(lines.len());
+        if start >= end {
+            return None;
+        }
+        return Some(lines[start..end].join("\n"));
+    }
+
+    fn definition(&self, params: &Json) -> Json {
+        let mcro = match self.macro_at(params) {
+            Some(mcro) => mcro,
+            None => return Json::Null,
+        };
+
+        let loc = mcro.loc();
+        let start = loc.col.saturating_sub(1);
+        let end = start + mcro.name().chars().count();
+        Json::object(vec![
+            ("uri", Json::Str(path_to_uri(&loc.file))),
+            ("range", Json::object(vec![
+                ("start", position(loc.line.saturating_sub(1), start)),
+                ("end", position(loc.line.saturating_sub(1), end)),
+            ])),
+        ])
+    }
+
+    fn hover(&self, params: &Json) -> Json {
+        let mcro = match self.macro_at(params) {
+            Some(mcro) => mcro,
+            None => return Json::Null,
+        };
+
+        let source = self.macro_source(mcro).unwrap_or_else(|| format!("macro {}", mcro.name()));
+        Json::object(vec![
+            ("contents", Json::object(vec![
+                ("kind", Json::Str("markdown".to_string())),
+                ("value", Json::Str(format!("```ktnack\n{}\n```\n\nDefined at {}", source, mcro.loc()))),
+            ])),
+        ])
+    }
+
+    fn completion(&self, params: &Json) -> Json {
+        let mut items: Vec<Json> = Vec::new();
+
+        let document = params.get("textDocument").and_then(|x| x.get("uri")).and_then(|x| x.as_str()).and_then(|x| self.documents.get(x));
+        if let Some(document) = document {
+            let mut names: Vec<&LMacro> = document.macros.values().collect();
+            names.sort_by(|a, b| a.name().cmp(b.name()));
+            for mcro in names {
+                let detail = if mcro.params().is_empty() {
+                    "macro".to_string()
+                } else {
+                    format!("macro {} in", mcro.params().join(" "))
+                };
+                items.push(Json::object(vec![
+                    ("label", Json::Str(mcro.name().clone())),
+                    ("kind", Json::Number(3)),
+                    ("detail", Json::Str(detail)),
+                ]));
+            }
+        }
+
+        for name in INTRINSICS.iter() {
+            items.push(Json::object(vec![
+                ("label", Json::Str(name.to_string())),
+                ("kind", Json::Number(14)),
+            ]));
+        }
+
+        return Json::Array(items);
+    }
+
+    fn capabilities() -> Json {
+        Json::object(vec![
+            ("capabilities", Json::object(vec![
+                ("textDocumentSync", Json::Number(1)),
+                ("definitionProvider", Json::Bool(true)),
+                ("hoverProvider", Json::Bool(true)),
+                ("completionProvider", Json::object(vec![])),
+            ])),
+            ("serverInfo", Json::object(vec![("name", Json::Str("ktnack".to_string()))])),
+        ])
+    }
+
+    /*
+        Handles one message, returning the result for a request or the error
+        code and message for one that can't be answered.
+     */
+    fn handle(&mut self, method: &str, params: &Json) -> Result<Json, (i64, String)> {
+        match method {
+            "initialize" => return Ok(Server::capabilities()),
+            "shutdown" => {
+                self.shutdown = true;
+                return Ok(Json::Null);
+            },
+            "textDocument/didOpen" => {
+                let document = params.get("textDocument");
+                let uri = document.and_then(|x| x.get("uri")).and_then(|x| x.as_str());
+                let text = document.and_then(|x| x.get("text")).and_then(|x| x.as_str());
+                if let (Some(uri), Some(text)) = (uri, text) {
+                    self.update(uri, text.to_string());
+                }
+            },
+            "textDocument/didChange" => {
+                let uri = params.get("textDocument").and_then(|x| x.get("uri")).and_then(|x| x.as_str());
+                let text = params.get("contentChanges").and_then(|x| x.as_array()).and_then(|x| x.last())
+                    .and_then(|x| x.get("text")).and_then(|x| x.as_str());
+                if let (Some(uri), Some(text)) = (uri, text) {
+                    self.update(uri, text.to_string());
+                }
+            },
+            "textDocument/didClose" => {
+                if let Some(uri) = params.get("textDocument").and_then(|x| x.get("uri")).and_then(|x| x.as_str()) {
+                    self.documents.remove(uri);
+                    publish(uri, Vec::new());
+                }
+            },
+            "textDocument/definition" => return Ok(self.definition(params)),
+            "textDocument/hover" => return Ok(self.hover(params)),
+            "textDocument/completion" => return Ok(self.completion(params)),
+            _ => return Err((-32601, format!("method not found: {}", method))),
+        }
+
+        return Ok(Json::Null);
+    }
+}
+
+/*
+    Serves one editor until it sends `exit`. Returns whether it asked to
+    shut down first.
+ */
+pub fn run_server(include_dirs: &[String], target: Target) -> bool {
+    let mut server = Server::new(include_dirs, target);
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+
+    loop {
+        let message = match read_message(&mut input) {
+            Ok(Some(message)) => message,
+            Ok(None) => break,
+            Err(error) => {
+                eprintln!("error: skipping malformed message: {}", error);
+                continue;
+            },
+        };
+
+        let method = message.get("method").and_then(|x| x.as_str()).unwrap_or("").to_string();
+        if method == "exit" {
+            return server.shutdown;
+        }
+
+        let params = message.get("params").cloned().unwrap_or(Json::Null);
+        let result = server.handle(&method, &params);
+
+        /* Notifications have no id and get no reply. */
+        let id = match message.get("id") {
+            Some(id) => id.clone(),
+            None => continue,
+        };
+
+        let reply = match result {
+            Ok(value) => ("result", value),
+            Err((code, text)) => ("error", Json::object(vec![
+                ("code", Json::Number(code)),
+                ("message", Json::Str(text)),
+            ])),
+        };
+
+        write_message(&Json::object(vec![
+            ("jsonrpc", Json::Str("2.0".to_string())),
+            ("id", id),
+            reply,
+        ]));
+    }
+
+    return server.shutdown;
+}
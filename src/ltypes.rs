@@ -1,5 +1,7 @@
 
 use std::collections::HashMap;
+use std::rc::Rc;
+use crate::error::CompileError;
 
 pub enum LValueType {
     Number(i64),
@@ -9,6 +11,65 @@ pub enum LValueType {
     None
 }
 
+#[derive(Clone, PartialEq)]
+pub struct LLoc {
+    pub file: String,
+    pub line: usize,
+    pub col: usize,
+    /* where the macro was used, for a word from a macro body */
+    pub from: Option<Rc<LLoc>>,
+}
+
+#[derive(Clone)]
+pub struct LToken {
+    pub text: String,
+    pub loc: LLoc,
+}
+
+/*
+    A piece of a source line as written, for tools that rewrite source
+    rather than compile it.
+ */
+pub enum LPiece {
+    Word(String),
+    Comment(String),
+}
+
+#[derive(Clone)]
+pub struct LWord {
+    pub value: LValueType,
+    pub loc: LLoc,
+}
+
+#[derive(Clone)]
+pub struct LOp {
+    pub op: LOpType,
+    /* the word the op was compiled from, as written */
+    pub word: String,
+    pub loc: LLoc,
+}
+
+/*
+    Size of the raw buffer behind `@`. Named regions are laid out after it.
+ */
+pub const MEM_CAPACITY: u64 = 640 * 1024;
+/* the most memory a program can declare, `@` included */
+pub const MEM_LIMIT: u64 = 1 << 30;
+
+#[derive(Clone)]
+pub struct LRegion {
+    pub name: String,
+    pub offset: u64,
+    pub size: u64,
+    pub loc: LLoc,
+}
+
+#[derive(Clone)]
+pub struct LMemory {
+    pub regions: Vec<LRegion>,
+    pub size: u64,
+}
+
 pub struct Loop {
     start: u64,
     cond: u64,
@@ -49,20 +110,33 @@ pub enum LOpType {
     NotEqual,
     Drop,
     Mem,
-    Load,
-    Store,
+    Load(u8, bool),
+    Store(u8),
     Puts(bool),
+    Proc(String, u64),
+    Call(String, u64),
+    Ret,
+    Syscall(u8),
+    Region(String, u64),
+    Jump(u64),
+    Exit,
+    Argc,
+    Argv,
+    Envp,
 }
 
 pub struct LMacro {
     name: String,
-    body: Vec<LValueType>,
+    params: Vec<String>,
+    body: Vec<LWord>,
+    loc: LLoc,
+    end: LLoc,
 }
 
 impl Clone for LValue {
     fn clone(&self) -> Self {
         match self {
-            Self::Number(x) => Self::Number(x.clone()),
+            Self::Number(x) => Self::Number(*x),
             Self::Text(x) => Self::Text(x.clone()),
         }
     }
@@ -86,11 +160,11 @@ impl Clone for LOpType {
             Self::Swap => Self::Swap,
             Self::Dup => Self::Dup,
             Self::Over => Self::Over,
-            Self::If(x) => Self::If(x.clone()),
-            Self::Else(x) => Self::Else(x.clone()),
+            Self::If(x) => Self::If(*x),
+            Self::Else(x) => Self::Else(*x),
             Self::While => Self::While,
-            Self::Do(x) => Self::Do(x.clone()),
-            Self::End(x) => Self::End(x.clone()),
+            Self::Do(x) => Self::Do(*x),
+            Self::End(x) => Self::End(*x),
             Self::Greater => Self::Greater,
             Self::Less => Self::Less,
             Self::GreaterEqual => Self::GreaterEqual,
@@ -99,9 +173,19 @@ impl Clone for LOpType {
             Self::NotEqual => Self::NotEqual,
             Self::Drop => Self::Drop,
             Self::Mem => Self::Mem,
-            Self::Load => Self::Load,
-            Self::Store => Self::Store,
-            Self::Puts(x) => Self::Puts(x.clone()),
+            Self::Load(x, y) => Self::Load(*x, *y),
+            Self::Store(x) => Self::Store(*x),
+            Self::Puts(x) => Self::Puts(*x),
+            Self::Proc(x, y) => Self::Proc(x.clone(), *y),
+            Self::Call(x, y) => Self::Call(x.clone(), *y),
+            Self::Ret => Self::Ret,
+            Self::Syscall(x) => Self::Syscall(*x),
+            Self::Exit => Self::Exit,
+            Self::Argc => Self::Argc,
+            Self::Argv => Self::Argv,
+            Self::Envp => Self::Envp,
+            Self::Region(x, y) => Self::Region(x.clone(), *y),
+            Self::Jump(x) => Self::Jump(*x),
         }
     }
 }
@@ -109,15 +193,90 @@ impl Clone for LOpType {
 impl Clone for LValueType {
     fn clone(&self) -> Self {
         match self {
-            Self::Number(x) => Self::Number(x.clone()),
+            Self::Number(x) => Self::Number(*x),
             Self::Symbol(x) => Self::Symbol(x.clone()),
             Self::Text(x) => Self::Text(x.clone()),
-            Self::Char(x) => Self::Char(x.clone()),
+            Self::Char(x) => Self::Char(*x),
             Self::None => Self::None,
         }
     }
 }
 
+impl LValueType {
+    /*
+        The value spelled the way it would be written in source.
+     */
+    pub fn text(&self) -> String {
+        match self {
+            LValueType::Number(x) => x.to_string(),
+            LValueType::Char(x) => format!("'{}'", char::from_u32(*x as u32).unwrap_or('?')),
+            LValueType::Text(x) => format!("{:?}", x),
+            LValueType::Symbol(x) => x.clone(),
+            LValueType::None => "_".to_string(),
+        }
+    }
+}
+
+impl LOpType {
+    /*
+        Number of values an op pops off the stack and pushes back, in that
+        order. Control flow ops only count the condition they consume.
+     */
+    pub fn stack_effect(&self) -> (usize, usize) {
+        match self {
+            LOpType::Nop(_) => (0, 0),
+            LOpType::Push(LValue::Number(_)) => (0, 1),
+            LOpType::Push(LValue::Text(_)) => (0, 2),
+            LOpType::Add | LOpType::Sub | LOpType::Mul | LOpType::Div | LOpType::Mod => (2, 1),
+            LOpType::Shl | LOpType::Shr | LOpType::Bor | LOpType::Band => (2, 1),
+            LOpType::Greater | LOpType::Less | LOpType::GreaterEqual => (2, 1),
+            LOpType::LessEqual | LOpType::Equal | LOpType::NotEqual => (2, 1),
+            LOpType::Log => (1, 0),
+            LOpType::Swap => (2, 2),
+            LOpType::Dup => (1, 2),
+            LOpType::Over => (2, 3),
+            LOpType::If(_) | LOpType::Do(_) => (1, 0),
+            LOpType::Else(_) | LOpType::While | LOpType::End(_) => (0, 0),
+            LOpType::Drop => (1, 0),
+            LOpType::Mem => (0, 1),
+            LOpType::Load(_, _) => (1, 1),
+            LOpType::Store(_) => (2, 0),
+            LOpType::Puts(_) => (2, 0),
+            LOpType::Proc(_, _) | LOpType::Call(_, _) | LOpType::Ret => (0, 0),
+            LOpType::Syscall(x) => (*x as usize + 1, 1),
+            LOpType::Region(_, _) => (0, 1),
+            LOpType::Jump(_) => (0, 0),
+            LOpType::Exit => (1, 0),
+            LOpType::Argc | LOpType::Argv | LOpType::Envp => (0, 1),
+        }
+    }
+
+    /*
+        The ip this op refers to, for ops that jump or call.
+     */
+    pub fn target(&self) -> Option<u64> {
+        match self {
+            LOpType::If(x) | LOpType::Else(x) | LOpType::Do(x) | LOpType::End(x) | LOpType::Jump(x) => Some(*x),
+            LOpType::Proc(_, x) | LOpType::Call(_, x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    /*
+        Instruction pointers execution may continue at after this op,
+        given the ip of the op itself.
+     */
+    pub fn successors(&self, ip: u64) -> Vec<u64> {
+        match self {
+            LOpType::If(x) | LOpType::Do(x) => vec![ip + 1, *x],
+            LOpType::Else(x) | LOpType::End(x) | LOpType::Jump(x) => vec![*x],
+            LOpType::Proc(_, x) => vec![*x],
+            LOpType::Ret | LOpType::Exit => vec![],
+            _ => vec![ip + 1],
+        }
+    }
+}
+
 impl std::fmt::Display for LValue {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -158,9 +317,20 @@ impl std::fmt::Display for LOpType {
             LOpType::End(x) => write!(f, "End(block:{})", x),
             LOpType::Drop => write!(f, "Drop"),
             LOpType::Mem => write!(f, "Mem"),
-            LOpType::Load => write!(f, "Load"),
-            LOpType::Store => write!(f, "Store"),
+            LOpType::Load(x, false) => write!(f, "Load({})", x),
+            LOpType::Load(x, true) => write!(f, "Load({}, signed)", x),
+            LOpType::Store(x) => write!(f, "Store({})", x),
             LOpType::Puts(x) => write!(f, "Puts(nl:{})", x),
+            LOpType::Proc(x, y) => write!(f, "Proc({}, end:{})", x, y),
+            LOpType::Call(x, y) => write!(f, "Call({}, proc:{})", x, y),
+            LOpType::Ret => write!(f, "Ret"),
+            LOpType::Syscall(x) => write!(f, "Syscall({})", x),
+            LOpType::Region(x, y) => write!(f, "Region({}, offset:{})", x, y),
+            LOpType::Jump(x) => write!(f, "Jump({})", x),
+            LOpType::Exit => write!(f, "Exit"),
+            LOpType::Argc => write!(f, "Argc"),
+            LOpType::Argv => write!(f, "Argv"),
+            LOpType::Envp => write!(f, "Envp"),
         }
     }
 }
@@ -177,37 +347,251 @@ impl std::fmt::Display for LValueType {
     }
 }
 
+impl LLoc {
+    pub fn new(file: &str, line: usize, col: usize) -> Self {
+        Self {
+            file: file.to_string(),
+            line,
+            col,
+            from: None,
+        }
+    }
+}
+
+impl LMemory {
+    pub fn new() -> Self {
+        Self {
+            regions: Vec::new(),
+            size: MEM_CAPACITY,
+        }
+    }
+
+    /*
+        Appends a region after everything allocated so far, aligned to
+        8 bytes, and returns its offset from the start of membuf.
+        Fails if the buffer would grow past MEM_LIMIT.
+     */
+    pub fn alloc(&mut self, name: &str, size: u64, loc: &LLoc) -> Result<u64, CompileError> {
+        let offset = self.size.next_multiple_of(8);
+        let end = match offset.checked_add(size) {
+            Some(end) if end <= MEM_LIMIT => end,
+            _ => return Err(CompileError::MemoryTooLarge(loc.clone(), name.to_string())),
+        };
+
+        self.regions.push(LRegion {
+            name: name.to_string(),
+            offset,
+            size,
+            loc: loc.clone(),
+        });
+        self.size = end;
+
+        Ok(offset)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&LRegion> {
+        self.regions.iter().find(|x| x.name == name)
+    }
+}
+
+impl LWord {
+    pub fn new(value: LValueType, loc: &LLoc) -> Self {
+        Self {
+            value,
+            loc: loc.clone(),
+        }
+    }
+}
+
+impl LOp {
+    pub fn new(op: LOpType, word: &str, loc: &LLoc) -> Self {
+        Self {
+            op,
+            word: word.to_string(),
+            loc: loc.clone(),
+        }
+    }
+}
+
+impl std::fmt::Display for LLoc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.col)
+    }
+}
+
+impl std::fmt::Display for LToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}@{}", self.text, self.loc)
+    }
+}
+
+impl std::fmt::Display for LWord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}@{}", self.value, self.loc)
+    }
+}
+
+impl std::fmt::Display for LOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}@{}", self.op, self.loc)
+    }
+}
+
+/*
+    One body being expanded, read a word at a time. The top level code is a
+    frame without a macro. `parent` is the frame the macro use was read
+    from, and each argument keeps the frame it was read from, so a macro
+    handed in as an argument isn't mistaken for one using itself.
+ */
+struct LExpansion<'a> {
+    mcro: Option<&'a LMacro>,
+    site: Option<Rc<LLoc>>,
+    words: &'a [LWord],
+    args: Vec<(LWord, usize)>,
+    parent: Option<usize>,
+    index: usize,
+}
+
+impl<'a> LExpansion<'a> {
+    /*
+        The next word and the frame it belongs to, `depth` being this one.
+     */
+    fn next(&mut self, depth: usize) -> Option<(LWord, usize)> {
+        let word = self.words.get(self.index)?;
+        self.index += 1;
+
+        if let (Some(mcro), LValueType::Symbol(sym)) = (self.mcro, &word.value) {
+            if let Some(index) = mcro.params.iter().position(|x| x == sym) {
+                return Some(self.args[index].clone());
+            }
+        }
+
+        let mut word = word.clone();
+        if self.site.is_some() {
+            word.loc.from = self.site.clone();
+        }
+        Some((word, depth))
+    }
+}
+
 impl LMacro {
-    pub fn new(name: &String, body: &Vec<LValueType>) -> Self {
+    /*
+        `loc` is where the name is written and `end` where the closing
+        `end` is, so the whole definition can be found again.
+     */
+    pub fn new(name: &str, params: &[String], body: &[LWord], loc: &LLoc, end: &LLoc) -> Self {
         Self {
             name: name.to_owned(),
-            body: body.clone(),
+            params: params.to_vec(),
+            body: body.to_vec(),
+            loc: loc.clone(),
+            end: end.clone(),
         }
     }
 
-    pub fn expand(&self, macros: &HashMap<String, LMacro>, max_depth: i32) -> Vec<LValueType> {
-        let mut result: Vec<LValueType> = Vec::new();        
-        if max_depth < 0 {
-            return result;
-        }
+    pub fn name(&self) -> &String {
+        &self.name
+    }
+
+    pub fn params(&self) -> &Vec<String> {
+        &self.params
+    }
+
+    pub fn loc(&self) -> &LLoc {
+        &self.loc
+    }
+
+    pub fn end(&self) -> &LLoc {
+        &self.end
+    }
 
-        for value in self.body.iter() {
-            match value {
-                LValueType::Symbol(sym) => {
-                    if let Some(mcro) = macros.get(sym) {
-                        let mut expanded = mcro.expand(macros, max_depth - 1);
-                        result.append(&mut expanded);
-                    } else {
-                        result.push(value.clone());
+    /*
+        Expands every macro used in `words`, with a stack of bodies being
+        read instead of recursion, so there is no limit on how deep macros
+        nest. A macro with parameters takes the words right after its name
+        as its arguments, one word each, with parameters substituted as the
+        body is read. When the body it is used in ends first, the arguments
+        are read from wherever that body was used, like the lexer reads on
+        past the end of a macro.
+
+        A body stays on the stack until a word is read past its end, so a
+        macro used as the last word of its own body is still on the stack
+        when it is used again. Any macro used while its own body is being
+        expanded would expand forever, and is reported with the chain of
+        macros that led back to it.
+     */
+    pub fn expand_words(words: &[LWord], macros: &HashMap<String, LMacro>) -> Result<Vec<LWord>, CompileError> {
+        let mut result: Vec<LWord> = Vec::new();
+        let mut frames: Vec<LExpansion> = vec![LExpansion {
+            mcro: None,
+            site: None,
+            words,
+            args: Vec::new(),
+            parent: None,
+            index: 0,
+        }];
+
+        while !frames.is_empty() {
+            let depth = frames.len() - 1;
+            let (value, origin) = match frames[depth].next(depth) {
+                Some(value) => value,
+                None => {
+                    frames.pop();
+                    continue;
+                }
+            };
+
+            let mcro = match &value.value {
+                LValueType::Symbol(sym) => macros.get(sym),
+                _ => None,
+            };
+
+            let mcro = match mcro {
+                Some(mcro) => mcro,
+                None => {
+                    result.push(value);
+                    continue;
+                }
+            };
+
+            /*
+                Arguments come after the name, continuing into the bodies
+                below once the current one runs out, so a macro can be
+                handed its arguments by the code around the macro using it.
+             */
+            let mut args: Vec<(LWord, usize)> = Vec::with_capacity(mcro.params.len());
+            for _ in 0..mcro.params.len() {
+                match frames.iter_mut().enumerate().rev().find_map(|(i, x)| x.next(i)) {
+                    Some(arg) => args.push(arg),
+                    None => return Err(CompileError::MissingMacroArgs(value.loc.clone(), mcro.name.clone(), mcro.params.len())),
+                }
+            }
+
+            /* the macros that led to this use, innermost first */
+            let mut chain: Vec<&str> = vec![mcro.name.as_str()];
+            let mut at = Some(origin);
+            while let Some(index) = at {
+                if let Some(outer) = frames[index].mcro {
+                    chain.push(outer.name.as_str());
+                    if std::ptr::eq(outer, mcro) {
+                        chain.reverse();
+                        return Err(CompileError::MacroCycle(value.loc.clone(), chain.join(" -> ")));
                     }
-                },
-                _ => {
-                    result.push(value.clone());
                 }
+                at = frames[index].parent;
             }
+
+            frames.push(LExpansion {
+                mcro: Some(mcro),
+                site: Some(Rc::new(value.loc.clone())),
+                words: &mcro.body,
+                args,
+                parent: Some(origin),
+                index: 0,
+            });
         }
 
-        result
+        Ok(result)
     }
 }
 
@@ -228,3 +612,27 @@ impl std::fmt::Debug for LValueType {
         write!(f, "{}", self)
     }
 }
+
+impl std::fmt::Debug for LLoc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl std::fmt::Debug for LToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl std::fmt::Debug for LWord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl std::fmt::Debug for LOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
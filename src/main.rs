@@ -9,41 +9,282 @@ mod base;
 mod compile;
 mod asm;
 mod strings;
+mod sim;
+mod error;
+mod check;
+mod optimize;
+mod json;
+mod emit;
+mod golden;
+mod lsp;
+mod fmt;
+mod repl;
+mod debug;
 
-use utils::{IS_DEBUG, file_exists};
-use args::{get_env_arg_cmds, ArgCommand};
-use cmds::cmd_handle_cmd;
+use utils::{set_debug, file_exists};
+use args::{get_env_arg_cmds, ArgCommand, Subcommand};
+use cmds::{cmd_handle_cmd, cmd_usage};
 use compile::Compiler;
+use emit::*;
 use asm::*;
+use std::path::Path;
+use std::process::{exit, Command};
+
+/*
+    Everything the command line sets for compiling a single file.
+ */
+struct Options {
+    mode: Subcommand,
+    target: Target,
+    opt_level: u8,
+    ret_stack: u64,
+    include_dirs: Vec<String>,
+    emit: Option<Emit>,
+    json: bool,
+    output: Option<String>,
+    keep: Option<bool>,
+    args: Vec<String>,
+    record: bool,
+    native: bool,
+    check_format: bool,
+}
 
 fn main() {
-    let commands = get_env_arg_cmds();
-    if commands.len() == 0 {
-        println!("No Ktnack file specified!");
-        return;
+    let commands = match get_env_arg_cmds() {
+        Ok(commands) => commands,
+        Err(error) => {
+            eprintln!("error: {}", error);
+            eprint!("{}", cmd_usage());
+            exit(1);
+        }
+    };
+
+    if commands.is_empty() {
+        eprintln!("No Ktnack file specified!");
+        eprint!("{}", cmd_usage());
+        exit(1);
     }
 
     let mut run_arg: Option<&String> = Option::None;
+    let mut handled = false;
+    let mut options = Options {
+        mode: Subcommand::Com,
+        target: Target::host(),
+        opt_level: optimize::OPT_NONE,
+        ret_stack: RET_STACK_SIZE,
+        include_dirs: Vec::new(),
+        emit: None,
+        json: false,
+        output: None,
+        keep: None,
+        args: Vec::new(),
+        record: false,
+        native: false,
+        check_format: false,
+    };
 
     for cmd in commands.iter() {
         if cmd_handle_cmd(cmd) {
-            continue;
-        } else if let ArgCommand::Run(file_name) = cmd {
+            handled = true;
+        } else if let ArgCommand::File(file_name) = cmd {
             run_arg = Option::Some(file_name);
+        } else if let ArgCommand::Sub(mode) = cmd {
+            options.mode = *mode;
+        } else if let ArgCommand::Target(x) = cmd {
+            options.target = *x;
+        } else if let ArgCommand::Optimize(level) = cmd {
+            options.opt_level = *level;
+        } else if let ArgCommand::RetStack(size) = cmd {
+            options.ret_stack = *size;
+        } else if let ArgCommand::IncludeDir(dir) = cmd {
+            options.include_dirs.push(dir.clone());
+        } else if let ArgCommand::Emit(kind) = cmd {
+            options.emit = Some(*kind);
+        } else if let ArgCommand::Json = cmd {
+            options.json = true;
+        } else if let ArgCommand::Debug = cmd {
+            set_debug(true);
+        } else if let ArgCommand::Output(path) = cmd {
+            options.output = Some(path.clone());
+        } else if let ArgCommand::Keep(keep) = cmd {
+            options.keep = Some(*keep);
+        } else if let ArgCommand::Args(args) = cmd {
+            options.args = args.clone();
+        } else if let ArgCommand::Record = cmd {
+            options.record = true;
+        } else if let ArgCommand::Native = cmd {
+            options.native = true;
+        } else if let ArgCommand::CheckFormat = cmd {
+            options.check_format = true;
         }
     }
 
-    if let Option::Some(file_name) = run_arg {
-        run(file_name);
+    /*
+        The language server and the REPL get their code from stdin.
+     */
+    if options.mode == Subcommand::Lsp {
+        exit(if lsp::run_server(&options.include_dirs, options.target) { 0 } else { 1 });
+    }
+
+    if options.mode == Subcommand::Repl {
+        exit(repl::run_repl(&options.include_dirs, options.target));
+    }
+
+    match run_arg {
+        Option::Some(file_name) => {
+            if !run(file_name, &options) {
+                exit(1);
+            }
+        },
+        Option::None => {
+            if !handled {
+                eprintln!("No Ktnack file specified!");
+                eprint!("{}", cmd_usage());
+                exit(1);
+            }
+        },
     }
 }
 
-fn run(file_name: &String) {
+/*
+    Prints one stage of the compiler instead of building the program.
+ */
+fn emit(file_name: &str, kind: Emit, options: &Options) -> Result<String, error::CompileError> {
+    let tokens = src::load_code(file_name, &options.include_dirs)?;
+    if kind == Emit::Tokens {
+        return Ok(emit_tokens(&tokens, options.json));
+    }
+
+    let words = src::expand_code(&tokens)?;
+    if kind == Emit::Expanded {
+        return Ok(emit_words(&words, options.json));
+    }
+
+    let mut compiler = Compiler::from_words(file_name, options.target, words)?;
+    compiler.set_ret_stack(options.ret_stack);
+    compiler.check()?;
+    compiler.optimize(options.opt_level);
+    if kind == Emit::Ops {
+        return Ok(emit_ops(&compiler, options.json));
+    }
+
+    let text = compiler.asm()?;
+    return Ok(emit_asm(file_name, &text, options.json));
+}
+
+fn run(file_name: &String, options: &Options) -> bool {
+    if options.mode == Subcommand::Test {
+        return golden::run_tests(file_name, &golden::TestOptions {
+            native: options.native,
+            record: options.record,
+            target: options.target,
+            opt_level: options.opt_level,
+            include_dirs: options.include_dirs.clone(),
+        });
+    }
+
+    if options.mode == Subcommand::Fmt {
+        return fmt::run_fmt(file_name, options.check_format);
+    }
+
     if !file_exists(file_name) {
-        println!("Ktnack file not found: {}", file_name);
-        return;
+        eprintln!("Ktnack file not found: {}", file_name);
+        return false;
+    }
+
+    if let Some(kind) = options.emit {
+        match emit(file_name, kind, options) {
+            Ok(text) => print!("{}", text),
+            Err(error) => {
+                eprintln!("{}", error);
+                return false;
+            }
+        }
+        return true;
     }
 
-    let mut compiler = Compiler::new(file_name.as_str());
-    compiler.compile();    
+    let mut compiler = match Compiler::new(file_name.as_str(), options.target, &options.include_dirs) {
+        Ok(compiler) => compiler,
+        Err(error) => {
+            eprintln!("{}", error);
+            return false;
+        }
+    };
+
+    compiler.set_ret_stack(options.ret_stack);
+
+    /*
+        Errors are reported against the program as written, the optimizer
+        only ever sees code that already passed the checker.
+     */
+    if let Err(error) = compiler.check() {
+        eprintln!("{}", error);
+        return false;
+    }
+
+    compiler.optimize(options.opt_level);
+
+    match options.mode {
+        Subcommand::Check => return true,
+        Subcommand::Debug => {
+            let mut args = vec![file_name.clone()];
+            args.extend(options.args.iter().cloned());
+            return debug::run_debugger(file_name, &compiler, &options.include_dirs, &args);
+        },
+        Subcommand::Sim => {
+            let mut args = vec![file_name.clone()];
+            args.extend(options.args.iter().cloned());
+
+            match compiler.simulate(&args) {
+                Some(0) => return true,
+                Some(code) => exit(code),
+                None => return false,
+            }
+        },
+        Subcommand::Com | Subcommand::Run | Subcommand::Test | Subcommand::Lsp | Subcommand::Fmt | Subcommand::Repl => {},
+    }
+
+    if let Some(path) = &options.output {
+        compiler.set_output(path);
+    }
+
+    let result = compiler.compile();
+
+    /*
+        `run` only needs the executable, `com` leaves the rest around to be
+        looked at, unless told otherwise either way.
+     */
+    if !options.keep.unwrap_or(options.mode == Subcommand::Com) {
+        compiler.clean();
+    }
+
+    if let Err(error) = result {
+        eprintln!("{}", error);
+        eprintln!("Failed to compile Ktnack program!");
+        return false;
+    }
+
+    if options.mode == Subcommand::Run {
+        exit(run_program(compiler.exe_name(), &options.args));
+    }
+
+    return true;
+}
+
+/*
+    Runs a freshly built program and returns its exit status. A bare name
+    would be looked up on PATH, so it is made relative to the current
+    directory first.
+ */
+fn run_program(exe_name: &str, args: &[String]) -> i32 {
+    let path = Path::new(exe_name);
+    let path = if path.components().count() == 1 { Path::new(".").join(path) } else { path.to_path_buf() };
+
+    match Command::new(&path).args(args).status() {
+        Ok(status) => status.code().unwrap_or(1),
+        Err(error) => {
+            eprintln!("Could not run {}: {}", path.display(), error);
+            1
+        }
+    }
 }
@@ -0,0 +1,270 @@
+use crate::ltypes::*;
+use std::collections::HashSet;
+
+/*
+    Optimization levels picked with -O. Level 1 only rewrites short runs of
+    straight line ops, level 2 also resolves branches on constants and drops
+    whatever can no longer be reached.
+ */
+pub const OPT_NONE: u8 = 0;
+pub const OPT_PEEPHOLE: u8 = 1;
+pub const OPT_FLOW: u8 = 2;
+
+/*
+    Works on the code in program order, unlike Compiler::code which is kept
+    reversed. Passes are repeated until none of them changes anything.
+ */
+pub fn optimize(code: &mut Vec<LOp>, level: u8) {
+    if level == OPT_NONE {
+        return;
+    }
+
+    loop {
+        let mut changed = peephole(code, level);
+        if level >= OPT_FLOW {
+            changed |= strip_unreachable(code);
+        }
+
+        if !changed {
+            break;
+        }
+    }
+}
+
+/*
+    Every ip some op can jump to. Only a set, so the order of `code` does
+    not matter and the reversed Compiler::code works as well.
+ */
+pub fn jump_targets(code: &[LOp]) -> HashSet<u64> {
+    let mut targets: HashSet<u64> = HashSet::new();
+    for op in code.iter() {
+        match &op.op {
+            LOpType::If(x) | LOpType::Else(x) | LOpType::Do(x) | LOpType::End(x) | LOpType::Jump(x) | LOpType::Proc(_, x) => {
+                targets.insert(*x);
+            },
+            LOpType::Call(_, x) => {
+                targets.insert(*x + 1);
+            },
+            _ => {},
+        }
+    }
+
+    return targets;
+}
+
+fn number(op: &LOp) -> Option<i64> {
+    match &op.op {
+        LOpType::Push(LValue::Number(x)) => Some(*x),
+        _ => None,
+    }
+}
+
+/*
+    Same arithmetic as the generated assembly and the simulator, so a folded
+    value is exactly what the program would have computed at runtime.
+ */
+fn fold(a: i64, b: i64, op: &LOpType) -> Option<i64> {
+    let (ua, ub) = (a as u64, b as u64);
+    let value = match op {
+        LOpType::Add => ua.wrapping_add(ub),
+        LOpType::Sub => ua.wrapping_sub(ub),
+        LOpType::Mul => ua.wrapping_mul(ub),
+        LOpType::Div if ub != 0 => ua / ub,
+        LOpType::Mod if ub != 0 => ua % ub,
+        LOpType::Shl => ua << (ub & 63),
+        LOpType::Shr => ua >> (ub & 63),
+        LOpType::Bor => ua | ub,
+        LOpType::Band => ua & ub,
+        LOpType::Greater => (a > b) as u64,
+        LOpType::Less => (a < b) as u64,
+        LOpType::GreaterEqual => (a >= b) as u64,
+        LOpType::LessEqual => (a <= b) as u64,
+        LOpType::Equal => (a == b) as u64,
+        LOpType::NotEqual => (a != b) as u64,
+        _ => return None,
+    };
+
+    return Some(value as i64);
+}
+
+fn address(op: &LOp) -> Option<(String, u64)> {
+    match &op.op {
+        LOpType::Mem => Some(("@".to_string(), 0)),
+        LOpType::Region(name, offset) => Some((name.clone(), *offset)),
+        _ => None,
+    }
+}
+
+/*
+    Rewrites the op at `ip` together with the ones following it. Returns the
+    replacement and how many ops it covers, or None when nothing applies.
+    Only the first op of a match may be a jump target, otherwise a jump
+    would land in the middle of something that no longer exists.
+ */
+fn rewrite(code: &[LOp], ip: usize, targets: &HashSet<u64>, level: u8) -> Option<(Vec<LOp>, usize)> {
+    let op = &code[ip];
+    let window = |count: usize| -> Option<&[LOp]> {
+        if ip + count > code.len() || (1..count).any(|i| targets.contains(&((ip + i) as u64))) {
+            return None;
+        }
+        Some(&code[ip..ip + count])
+    };
+
+    if let Some(ops) = window(3) {
+        if let (Some(a), Some(b)) = (number(&ops[0]), number(&ops[1])) {
+            if let Some(value) = fold(a, b, &ops[2].op) {
+                return Some((vec![LOp::new(LOpType::Push(LValue::Number(value)), &op.word, &op.loc)], 3));
+            }
+        }
+
+        if let (Some((name, offset)), Some(n), LOpType::Add) = (address(&ops[0]), number(&ops[1]), &ops[2].op) {
+            /*
+                The offset ends up as a rip relative displacement, so it is
+                only folded while it stays within the largest buffer a
+                program can declare, well inside 32 bits.
+             */
+            if let Some(offset) = offset.checked_add(n as u64).filter(|x| n >= 0 && *x <= MEM_LIMIT) {
+                return Some((vec![LOp::new(LOpType::Region(name, offset), &op.word, &op.loc)], 3));
+            }
+        }
+    }
+
+    if let Some(ops) = window(2) {
+        let pure = number(&ops[0]).is_some() || address(&ops[0]).is_some() || matches!(ops[0].op, LOpType::Dup | LOpType::Argc | LOpType::Argv | LOpType::Envp);
+        if pure && matches!(ops[1].op, LOpType::Drop) {
+            return Some((Vec::new(), 2));
+        }
+
+        if level >= OPT_FLOW {
+            if let (Some(x), LOpType::If(block_ip) | LOpType::Do(block_ip)) = (number(&ops[0]), &ops[1].op) {
+                if x != 0 {
+                    return Some((Vec::new(), 2));
+                }
+                return Some((vec![LOp::new(LOpType::Jump(*block_ip), &ops[1].word, &ops[1].loc)], 2));
+            }
+        }
+    }
+
+    if level >= OPT_FLOW {
+        if let LOpType::Else(x) | LOpType::End(x) | LOpType::Jump(x) = &op.op {
+            if *x == ip as u64 + 1 {
+                return Some((Vec::new(), 1));
+            }
+        }
+    }
+
+    return None;
+}
+
+fn peephole(code: &mut Vec<LOp>, level: u8) -> bool {
+    let targets = jump_targets(code);
+    let mut slots: Vec<Option<LOp>> = Vec::with_capacity(code.len());
+    let mut changed = false;
+
+    let mut ip = 0;
+    while ip < code.len() {
+        match rewrite(code, ip, &targets, level) {
+            Some((ops, count)) => {
+                /*
+                    The replacement is never longer than what it replaces,
+                    so it fits in the old slots and nothing else moves yet.
+                 */
+                for i in 0..count {
+                    slots.push(ops.get(i).cloned());
+                }
+                ip += count;
+                changed = true;
+            },
+            None => {
+                slots.push(Some(code[ip].clone()));
+                ip += 1;
+            },
+        }
+    }
+
+    if changed {
+        compact(code, slots);
+    }
+
+    return changed;
+}
+
+/*
+    Reachability from the start of the program. Running into a Proc op only
+    skips over it, the procedure itself is live once a live call refers to
+    it, and its Proc op is kept then since that is where its label goes.
+ */
+fn strip_unreachable(code: &mut Vec<LOp>) -> bool {
+    let len = code.len() as u64;
+    let mut seen = vec![false; code.len()];
+    let mut live = vec![false; code.len()];
+    let mut work: Vec<u64> = vec![0];
+
+    while let Some(ip) = work.pop() {
+        if ip >= len || seen[ip as usize] {
+            continue;
+        }
+
+        seen[ip as usize] = true;
+        let op = &code[ip as usize].op;
+        match op {
+            LOpType::Proc(_, _) => {},
+            LOpType::Call(_, proc_ip) => {
+                live[ip as usize] = true;
+                live[*proc_ip as usize] = true;
+                work.push(*proc_ip + 1);
+            },
+            _ => {
+                live[ip as usize] = true;
+            },
+        }
+
+        for next in op.successors(ip) {
+            work.push(next);
+        }
+    }
+
+    if live.iter().all(|x| *x) {
+        return false;
+    }
+
+    let slots = code.iter().zip(live.iter())
+        .map(|(op, live)| if *live { Some(op.clone()) } else { None })
+        .collect();
+    compact(code, slots);
+
+    return true;
+}
+
+/*
+    Removes the empty slots and moves every jump along with the code. A jump
+    to a removed op lands on the first op kept after it, which is what
+    running through the removed ops would have reached.
+ */
+fn compact(code: &mut Vec<LOp>, slots: Vec<Option<LOp>>) {
+    let mut remap: Vec<u64> = Vec::with_capacity(slots.len() + 1);
+    let mut kept: u64 = 0;
+    for slot in slots.iter() {
+        remap.push(kept);
+        if slot.is_some() {
+            kept += 1;
+        }
+    }
+    remap.push(kept);
+
+    let at = |x: &u64| remap[*x as usize];
+    code.clear();
+    for op in slots.into_iter().flatten() {
+        let kind = match op.op {
+            LOpType::If(x) => LOpType::If(at(&x)),
+            LOpType::Else(x) => LOpType::Else(at(&x)),
+            LOpType::Do(x) => LOpType::Do(at(&x)),
+            LOpType::End(x) => LOpType::End(at(&x)),
+            LOpType::Jump(x) => LOpType::Jump(at(&x)),
+            LOpType::Proc(name, x) => LOpType::Proc(name, at(&x)),
+            LOpType::Call(name, x) => LOpType::Call(name, at(&x)),
+            kind => kind,
+        };
+        code.push(LOp::new(kind, &op.word, &op.loc));
+    }
+}
@@ -0,0 +1,176 @@
+use std::io::{self, BufRead, Write};
+use crate::ltypes::*;
+use crate::error::CompileError;
+use crate::compile::Compiler;
+use crate::asm::Target;
+use crate::sim::{Simulator, SimState};
+use crate::src::{Includes, get_code_tokens, expand_code, opens_block};
+
+const REPL_FILE: &str = "repl";
+
+/*
+    Everything kept from one input to the next. Definitions are kept as the
+    tokens they were read from and compiled again in front of every input,
+    code is only ever run once.
+ */
+struct Session {
+    includes: Includes,
+    defs: Vec<LToken>,
+    state: SimState,
+    target: Target,
+}
+
+/*
+    How many blocks are still open at the end of the tokens.
+ */
+fn open_blocks(tokens: &[LToken]) -> usize {
+    let mut depth: usize = 0;
+    for token in tokens.iter() {
+        if opens_block(&token.text) {
+            depth += 1;
+        } else if token.text == "end" {
+            depth = depth.saturating_sub(1);
+        }
+    }
+    return depth;
+}
+
+/*
+    Splits tokens into the top level definitions and the code to run.
+ */
+fn split_defs(tokens: Vec<LToken>) -> (Vec<LToken>, Vec<LToken>) {
+    let mut defs: Vec<LToken> = Vec::new();
+    let mut code: Vec<LToken> = Vec::new();
+    let mut depth: usize = 0;
+    let mut in_def = false;
+
+    for token in tokens {
+        if depth == 0 {
+            in_def = matches!(token.text.as_str(), "macro" | "proc" | "memory" | "const");
+        }
+
+        if opens_block(&token.text) {
+            depth += 1;
+        } else if token.text == "end" {
+            depth = depth.saturating_sub(1);
+        }
+
+        if in_def {
+            defs.push(token);
+        } else {
+            code.push(token);
+        }
+    }
+
+    return (defs, code);
+}
+
+fn format_stack(stack: &[u64]) -> String {
+    if stack.is_empty() {
+        return "stack: (empty)".to_string();
+    }
+
+    let values: Vec<String> = stack.iter().map(|x| (*x as i64).to_string()).collect();
+    format!("stack: {}", values.join(" "))
+}
+
+impl Session {
+    fn new(include_dirs: &[String], target: Target) -> Self {
+        Self {
+            includes: Includes::new(include_dirs),
+            defs: Vec::new(),
+            state: SimState::new(),
+            target,
+        }
+    }
+
+    /*
+        Runs one input. Nothing is kept from an input that fails to compile
+        or run, so the stack and definitions are as they were before it.
+        Returns the exit status if the input exited.
+     */
+    fn eval(&mut self, text: &str) -> Result<Option<i32>, String> {
+        let mut includes = self.includes.clone();
+        let tokens = includes.load_snippet(REPL_FILE, text).map_err(|e| e.to_string())?;
+        let (mut defs, code) = split_defs(tokens);
+
+        let mut all = self.defs.clone();
+        all.append(&mut defs);
+        let defs_len = all.len();
+        all.extend(code);
+
+        let words = expand_code(&all).map_err(|e| e.to_string())?;
+        let compiler = Compiler::from_words(REPL_FILE, self.target, words).map_err(|e| e.to_string())?;
+
+        let state = SimState {
+            stack: self.state.stack.clone(),
+            mem: self.state.mem.clone(),
+            strs: self.state.strs.clone(),
+        };
+
+        let stdout = io::stdout();
+        let mut out = io::BufWriter::new(stdout.lock());
+        let mut sim = Simulator::resume(&compiler, state);
+        sim.set_args(&[REPL_FILE.to_string()]);
+        let code = sim.run(&mut out)?;
+        let finished = sim.finished();
+        drop(out);
+
+        all.truncate(defs_len);
+        self.defs = all;
+        self.includes = includes;
+        self.state = sim.into_state();
+
+        if finished {
+            return Ok(None);
+        }
+        return Ok(Some(code));
+    }
+}
+
+/*
+    Reads Ktnack code from stdin, a line at a time, and runs it in the
+    simulator, printing the stack after each. A line that leaves a block
+    open is continued on the next. Returns the exit status to leave with.
+ */
+pub fn run_repl(include_dirs: &[String], target: Target) -> i32 {
+    let mut session = Session::new(include_dirs, target);
+    let stdin = io::stdin();
+    let mut text = String::new();
+
+    loop {
+        print!("{}", if text.is_empty() { "> " } else { "... " });
+        let _ = io::stdout().flush();
+
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => {
+                println!();
+                return 0;
+            },
+            Ok(_) => {},
+        }
+        text.push_str(&line);
+
+        /*
+            Only wait for more lines while the input can still be read,
+            errors are reported once it is complete.
+         */
+        match get_code_tokens(&text, REPL_FILE) {
+            Ok(tokens) if open_blocks(&tokens) > 0 => continue,
+            Err(CompileError::UnterminatedComment(_)) => continue,
+            _ => {},
+        }
+
+        let input = std::mem::take(&mut text);
+        if input.trim().is_empty() {
+            continue;
+        }
+
+        match session.eval(&input) {
+            Ok(Some(code)) => return code,
+            Ok(None) => println!("{}", format_stack(&session.state.stack)),
+            Err(error) => eprintln!("{}", error),
+        }
+    }
+}
@@ -0,0 +1,458 @@
+use std::collections::HashMap;
+use std::env;
+use std::io;
+use std::io::{Read, Write};
+use crate::ltypes::*;
+use crate::compile::Compiler;
+
+/*
+    Simulated address space. The addresses are arbitrary, they only need to
+    keep membuf, the string literals and the command line apart so a bad
+    pointer is caught instead of silently reading the wrong buffer.
+ */
+pub const MEM_ADDR: u64 = 0x1000_0000;
+pub const STR_ADDR: u64 = 0x2000_0000;
+pub const ARGS_ADDR: u64 = 0x3000_0000;
+
+const SYS_READ: u64 = 0;
+const SYS_WRITE: u64 = 1;
+const SYS_EXIT: u64 = 60;
+const SYS_EXIT_GROUP: u64 = 231;
+const EBADF: i64 = 9;
+
+enum SysResult {
+    Value(u64),
+    Exit(i32),
+}
+
+pub struct Simulator<'a> {
+    compiler: &'a Compiler,
+    stack: Vec<u64>,
+    ret_stack: Vec<u64>,
+    mem: Vec<u8>,
+    strs: Vec<u8>,
+    str_addrs: HashMap<u64, u64>,
+    args: Vec<u8>,
+    argc: u64,
+    ip: u64,
+}
+
+/*
+    What a simulation leaves behind, so another program can carry on from
+    it. String literals are kept so addresses pushed earlier stay valid.
+ */
+pub struct SimState {
+    pub stack: Vec<u64>,
+    pub mem: Vec<u8>,
+    pub strs: Vec<u8>,
+}
+
+impl SimState {
+    pub fn new() -> Self {
+        Self {
+            stack: Vec::new(),
+            mem: Vec::new(),
+            strs: Vec::new(),
+        }
+    }
+}
+
+impl<'a> Simulator<'a> {
+    pub fn new(compiler: &'a Compiler) -> Self {
+        return Simulator::resume(compiler, SimState::new());
+    }
+
+    /*
+        Starts the program with the stack and memory of an earlier run.
+        Memory grows to fit the program, what was already there is kept.
+     */
+    pub fn resume(compiler: &'a Compiler, state: SimState) -> Self {
+        let mut strs = state.strs;
+        let mut str_addrs: HashMap<u64, u64> = HashMap::new();
+
+        let mut ip: u64 = 0;
+        while let Some(op) = compiler.op(ip) {
+            if let LOpType::Push(LValue::Text(text)) = &op.op {
+                str_addrs.insert(ip, STR_ADDR + strs.len() as u64);
+                strs.extend_from_slice(text.as_bytes());
+            }
+            ip += 1;
+        }
+
+        /*
+            A fresh buffer is allocated zeroed, which leaves the pages of a
+            large declaration untouched until the program uses them.
+         */
+        let mut mem = state.mem;
+        if mem.is_empty() {
+            mem = vec![0; compiler.memory.size as usize];
+        } else if mem.len() < compiler.memory.size as usize {
+            mem.resize(compiler.memory.size as usize, 0);
+        }
+
+        Self {
+            compiler,
+            stack: state.stack,
+            ret_stack: Vec::new(),
+            mem,
+            strs,
+            str_addrs,
+            args: Vec::new(),
+            argc: 0,
+            ip: 0,
+        }
+    }
+
+    /*
+        Lays out the command line like the C runtime hands it to main: the
+        argv pointers, then the envp pointers, each ending in a null
+        pointer, then the strings they point to.
+     */
+    pub fn set_args(&mut self, args: &[String]) {
+        let vars: Vec<String> = env::vars_os().map(|(k, v)| format!("{}={}", k.to_string_lossy(), v.to_string_lossy())).collect();
+
+        let mut strings: Vec<u8> = Vec::new();
+        let mut pointers: Vec<u64> = Vec::new();
+        let table_size = (args.len() + vars.len() + 2) as u64 * 8;
+        for list in [args, vars.as_slice()] {
+            for text in list.iter() {
+                pointers.push(ARGS_ADDR + table_size + strings.len() as u64);
+                strings.extend_from_slice(text.as_bytes());
+                strings.push(0);
+            }
+            pointers.push(0);
+        }
+
+        self.args = pointers.iter().flat_map(|x| x.to_le_bytes()).collect();
+        self.args.extend(strings);
+        self.argc = args.len() as u64;
+    }
+
+    pub fn into_state(self) -> SimState {
+        SimState {
+            stack: self.stack,
+            mem: self.mem,
+            strs: self.strs,
+        }
+    }
+
+    /*
+        Whether the program ran to its end, rather than stopping with an
+        exit syscall.
+     */
+    pub fn finished(&self) -> bool {
+        self.compiler.op(self.ip).is_none()
+    }
+
+    fn error(&self, text: &str) -> String {
+        match self.compiler.op(self.ip) {
+            Some(op) => format!("{}: {}", op.loc, text),
+            None => format!("ip {}: {}", self.ip, text),
+        }
+    }
+
+    fn pop(&mut self) -> Result<u64, String> {
+        match self.stack.pop() {
+            Some(x) => Ok(x),
+            None => Err(self.error("stack underflow")),
+        }
+    }
+
+    fn peek(&self, depth: usize) -> Result<u64, String> {
+        if depth >= self.stack.len() {
+            return Err(self.error("stack underflow"));
+        }
+
+        return Ok(self.stack[self.stack.len() - 1 - depth]);
+    }
+
+    fn bytes(&mut self, addr: u64, size: usize) -> Result<&mut [u8], String> {
+        let (base, len) = if addr >= ARGS_ADDR {
+            (ARGS_ADDR, self.args.len())
+        } else if addr >= STR_ADDR {
+            (STR_ADDR, self.strs.len())
+        } else {
+            (MEM_ADDR, self.mem.len())
+        };
+
+        let index = addr.wrapping_sub(base) as usize;
+        if index > len || len - index < size {
+            return Err(self.error(format!("invalid memory access at address {}", addr).as_str()));
+        }
+
+        if addr >= ARGS_ADDR {
+            Ok(&mut self.args[index..index + size])
+        } else if addr >= STR_ADDR {
+            Ok(&mut self.strs[index..index + size])
+        } else {
+            Ok(&mut self.mem[index..index + size])
+        }
+    }
+
+    fn byte(&mut self, addr: u64) -> Result<&mut u8, String> {
+        Ok(&mut self.bytes(addr, 1)?[0])
+    }
+
+    /*
+        Only the calls needed to talk to the terminal and stop the program
+        are simulated, with Linux x86-64 numbering and return values.
+     */
+    fn syscall(&mut self, number: u64, args: &[u64], out: &mut dyn Write) -> Result<SysResult, String> {
+        let arg = |i: usize| args.get(i).copied().unwrap_or(0);
+        match number {
+            SYS_READ => {
+                if arg(0) != 0 {
+                    return Ok(SysResult::Value((-EBADF) as u64));
+                }
+
+                out.flush().map_err(|e| e.to_string())?;
+                let buf = self.bytes(arg(1), arg(2) as usize)?;
+                let count = io::stdin().read(buf).map_err(|e| e.to_string())?;
+                Ok(SysResult::Value(count as u64))
+            },
+            SYS_WRITE => {
+                let fd = arg(0);
+                let data = self.bytes(arg(1), arg(2) as usize)?.to_vec();
+                if fd == 1 {
+                    out.write_all(&data).map_err(|e| e.to_string())?;
+                } else if fd == 2 {
+                    out.flush().map_err(|e| e.to_string())?;
+                    io::stderr().write_all(&data).map_err(|e| e.to_string())?;
+                } else {
+                    return Ok(SysResult::Value((-EBADF) as u64));
+                }
+
+                Ok(SysResult::Value(data.len() as u64))
+            },
+            SYS_EXIT | SYS_EXIT_GROUP => Ok(SysResult::Exit(arg(0) as i32)),
+            _ => Err(self.error(format!("syscall {} is not supported by the simulator", number).as_str())),
+        }
+    }
+
+    fn binop(&mut self, f: fn(u64, u64) -> u64) -> Result<(), String> {
+        let b = self.pop()?;
+        let a = self.pop()?;
+        self.stack.push(f(a, b));
+        Ok(())
+    }
+
+    fn cmpop(&mut self, f: fn(i64, i64) -> bool) -> Result<(), String> {
+        let b = self.pop()? as i64;
+        let a = self.pop()? as i64;
+        self.stack.push(f(a, b) as u64);
+        Ok(())
+    }
+
+    /*
+        Output goes through printf in the compiled program, one character at
+        a time with "%s", so a zero byte never reaches stdout. Mirror that here.
+     */
+    fn put_char(out: &mut dyn Write, c: u8) -> Result<(), String> {
+        if c == 0 {
+            return Ok(());
+        }
+
+        out.write_all(&[c]).map_err(|e| e.to_string())
+    }
+
+    /*
+        Runs the program to completion and returns its exit status.
+     */
+    pub fn run(&mut self, out: &mut dyn Write) -> Result<i32, String> {
+        loop {
+            if let Some(code) = self.step(out)? {
+                return Ok(code);
+            }
+        }
+    }
+
+    pub fn ip(&self) -> u64 {
+        self.ip
+    }
+
+    pub fn stack(&self) -> &[u64] {
+        &self.stack
+    }
+
+    /*
+        Copies `count` bytes of memory starting at `addr`.
+     */
+    pub fn read(&mut self, addr: u64, count: usize) -> Result<Vec<u8>, String> {
+        Ok(self.bytes(addr, count)?.to_vec())
+    }
+
+    /*
+        Runs the op at ip. Returns the exit status once the program has
+        stopped, either by running off its end or with an exit syscall.
+     */
+    pub fn step(&mut self, out: &mut dyn Write) -> Result<Option<i32>, String> {
+        if let Some(op) = self.compiler.op(self.ip) {
+            let mut next = self.ip + 1;
+
+            match &op.op {
+                LOpType::Push(LValue::Number(x)) => {
+                    self.stack.push(*x as u64);
+                },
+                LOpType::Push(LValue::Text(text)) => {
+                    let addr = self.str_addrs[&self.ip];
+                    self.stack.push(addr);
+                    self.stack.push(text.len() as u64);
+                },
+                LOpType::Add => self.binop(|a, b| a.wrapping_add(b))?,
+                LOpType::Sub => self.binop(|a, b| a.wrapping_sub(b))?,
+                LOpType::Mul => self.binop(|a, b| a.wrapping_mul(b))?,
+                LOpType::Div | LOpType::Mod => {
+                    let b = self.pop()?;
+                    let a = self.pop()?;
+                    if b == 0 {
+                        return Err(self.error("division by zero"));
+                    }
+
+                    self.stack.push(if let LOpType::Div = op.op { a / b } else { a % b });
+                },
+                LOpType::Shl => self.binop(|a, b| a << (b & 63))?,
+                LOpType::Shr => self.binop(|a, b| a >> (b & 63))?,
+                LOpType::Bor => self.binop(|a, b| a | b)?,
+                LOpType::Band => self.binop(|a, b| a & b)?,
+                LOpType::Log => {
+                    let x = self.pop()?;
+                    writeln!(out, "{}", x as i64).map_err(|e| e.to_string())?;
+                },
+                LOpType::Drop => {
+                    self.pop()?;
+                },
+                LOpType::Dup => {
+                    let x = self.peek(0)?;
+                    self.stack.push(x);
+                },
+                LOpType::Over => {
+                    let x = self.peek(1)?;
+                    self.stack.push(x);
+                },
+                LOpType::Swap => {
+                    let b = self.pop()?;
+                    let a = self.pop()?;
+                    self.stack.push(b);
+                    self.stack.push(a);
+                },
+                LOpType::Greater => self.cmpop(|a, b| a > b)?,
+                LOpType::Less => self.cmpop(|a, b| a < b)?,
+                LOpType::GreaterEqual => self.cmpop(|a, b| a >= b)?,
+                LOpType::LessEqual => self.cmpop(|a, b| a <= b)?,
+                LOpType::Equal => self.cmpop(|a, b| a == b)?,
+                LOpType::NotEqual => self.cmpop(|a, b| a != b)?,
+                LOpType::If(block_ip) | LOpType::Do(block_ip) => {
+                    if self.pop()? == 0 {
+                        next = *block_ip;
+                    }
+                },
+                LOpType::Else(block_ip) | LOpType::End(block_ip) | LOpType::Jump(block_ip) => {
+                    next = *block_ip;
+                },
+                LOpType::While => {},
+                LOpType::Mem => {
+                    self.stack.push(MEM_ADDR);
+                },
+                LOpType::Region(_, offset) => {
+                    self.stack.push(MEM_ADDR + offset);
+                },
+                LOpType::Argc => {
+                    self.stack.push(self.argc);
+                },
+                LOpType::Argv => {
+                    self.stack.push(ARGS_ADDR);
+                },
+                LOpType::Envp => {
+                    self.stack.push(ARGS_ADDR + (self.argc + 1) * 8);
+                },
+                LOpType::Load(bits, signed) => {
+                    let size = *bits as usize / 8;
+                    let addr = self.pop()?;
+                    let mut data = [0u8; 8];
+                    data[..size].copy_from_slice(self.bytes(addr, size)?);
+
+                    let mut value = u64::from_le_bytes(data);
+                    if *signed && size < 8 {
+                        let shift = 64 - *bits as u32;
+                        value = (((value << shift) as i64) >> shift) as u64;
+                    }
+                    self.stack.push(value);
+                },
+                LOpType::Store(bits) => {
+                    let size = *bits as usize / 8;
+                    let addr = self.pop()?;
+                    let value = self.pop()?;
+                    self.bytes(addr, size)?.copy_from_slice(&value.to_le_bytes()[..size]);
+                },
+                LOpType::Puts(nl) => {
+                    /*
+                        address count, printed at least once like the
+                        do-while loop in the generated assembly
+                     */
+                    let nl = *nl;
+                    let mut count = self.pop()?;
+                    let mut addr = self.pop()?;
+                    loop {
+                        let c = *self.byte(addr)?;
+                        Self::put_char(out, c)?;
+                        count = count.wrapping_sub(1);
+                        addr = addr.wrapping_add(1);
+                        if count as i64 <= 0 {
+                            break;
+                        }
+                    }
+
+                    if nl {
+                        Self::put_char(out, b'\n')?;
+                    }
+                },
+                LOpType::Proc(_, skip_ip) => {
+                    next = *skip_ip;
+                },
+                LOpType::Call(_, proc_ip) => {
+                    if self.ret_stack.len() as u64 >= self.compiler.ret_stack() {
+                        return Err(self.error("return stack overflow, procedures are nested too deep"));
+                    }
+                    self.ret_stack.push(next);
+                    next = *proc_ip + 1;
+                },
+                LOpType::Ret => {
+                    next = match self.ret_stack.pop() {
+                        Some(x) => x,
+                        None => return Err(self.error("return stack underflow")),
+                    };
+                },
+                LOpType::Syscall(count) => {
+                    let number = self.pop()?;
+                    let mut args: Vec<u64> = vec![0; *count as usize];
+                    for arg in args.iter_mut().rev() {
+                        *arg = self.pop()?;
+                    }
+
+                    match self.syscall(number, &args, out)? {
+                        SysResult::Value(x) => self.stack.push(x),
+                        SysResult::Exit(code) => {
+                            out.flush().map_err(|e| e.to_string())?;
+                            return Ok(Some(code));
+                        },
+                    }
+                },
+                LOpType::Exit => {
+                    let code = self.pop()?;
+                    out.flush().map_err(|e| e.to_string())?;
+                    return Ok(Some(code as i32));
+                },
+                LOpType::Nop(x) => {
+                    return Err(self.error(format!("Not implemented! Nop({})", x).as_str()));
+                },
+            }
+
+            self.ip = next;
+            return Ok(None);
+        }
+
+        out.flush().map_err(|e| e.to_string())?;
+
+        return Ok(Some(0));
+    }
+}
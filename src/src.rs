@@ -1,10 +1,28 @@
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufReader, BufRead};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
 use crate::ltypes::*;
 use crate::strings::*;
+use crate::error::CompileError;
+use crate::debugln;
+
+pub fn convert_token_to_lword(token: &LToken) -> Result<LWord, CompileError> {
+    let value = if token.text.starts_with("\"") || token.text.starts_with("'") {
+        let text = format_string_token(&token.text);
+        if text.starts_with("'") && text.chars().count() != 3 {
+            return Err(CompileError::InvalidCharLiteral(token.loc.clone(), token.text.clone()));
+        }
+
+        convert_string_to_lvalue(&text)
+    } else {
+        convert_string_to_lvalue(&token.text)
+    };
+
+    Ok(LWord::new(value, &token.loc))
+}
 
-pub fn convert_string_to_lvalue(s: &String) -> LValueType {
+pub fn convert_string_to_lvalue(s: &str) -> LValueType {
     if s.starts_with("\"") && s.ends_with("\"") {
         return LValueType::Text((s[1..s.len() - 1]).to_string());
     } else if s.starts_with("'") && s.ends_with("'") {
@@ -15,24 +33,53 @@ pub fn convert_string_to_lvalue(s: &String) -> LValueType {
         return LValueType::Number(i);
     }
 
-    return LValueType::Symbol(s.clone());
+    return LValueType::Symbol(s.to_string());
+}
+
+/*
+    Every word the lexer knows without a definition, keywords included.
+ */
+pub const INTRINSICS: &[&str] = &[
+    "add", "+", "sub", "-", "mul", "*", "div", "/", "mod", "%", "shl", "<<", "shr", ">>", "bor", "|", "band", "&",
+    "log", ".", "swap", "s", "dup", "over", "drop", ">", "<", ">=", "<=", "=", "!=",
+    "if", "else", "while", "do", "end", "macro", "proc", "memory", "const", "inc",
+    "store", "S", "store8", "S8", "store16", "S16", "store32", "S32", "store64", "S64",
+    "load", "L", "load8", "L8", "load16", "L16", "load32", "L32", "load64", "L64",
+    "load8s", "L8s", "load16s", "L16s", "load32s", "L32s", "@", "P", "p",
+    "syscall0", "syscall1", "syscall2", "syscall3", "syscall4", "syscall5", "syscall6", "exit",
+    "argc", "argv", "envp",
+];
+
+/*
+    Names a const, memory region or procedure can't take, since the lexer
+    would read them as the intrinsic instead.
+ */
+fn is_intrinsic(name: &str) -> bool {
+    INTRINSICS.contains(&name)
 }
 
-fn load_macros_and_expand(raw_code: Vec<LValueType>) -> Vec<LValueType> {
+fn load_macros_and_expand(raw_code: Vec<LWord>) -> Result<Vec<LWord>, CompileError> {
+    let (code, macros) = load_macros(raw_code)?;
+    return LMacro::expand_words(&code, &macros);
+}
+
+/*
+    Takes every macro definition out of the code, returning what is left
+    and the macros by name.
+ */
+pub fn load_macros(raw_code: Vec<LWord>) -> Result<(Vec<LWord>, HashMap<String, LMacro>), CompileError> {
     let mut macros: HashMap<String, LMacro> = HashMap::new();
-    let mut code: Vec<LValueType> = Vec::new();
+    let mut code: Vec<LWord> = Vec::new();
 
     fn is_macro_end(lvalue: Option<LValueType>, count: &mut i32) -> bool {
-        if let Some(lvalue) = lvalue {
-            if let LValueType::Symbol(sym) = lvalue {
-                if sym == "end" {
-                    if *count == 0 {
-                        return true;
-                    }
-                    *count -= 1;
-                } else if sym == "if" || sym == "while" {
-                    *count += 1;
+        if let Some(LValueType::Symbol(sym)) = lvalue {
+            if sym == "end" {
+                if *count == 0 {
+                    return true;
                 }
+                *count -= 1;
+            } else if sym == "if" || sym == "while" || sym == "proc" || sym == "memory" || sym == "const" {
+                *count += 1;
             }
         }
 
@@ -40,47 +87,41 @@ fn load_macros_and_expand(raw_code: Vec<LValueType>) -> Vec<LValueType> {
     }
 
     fn get_macro_text(lvalue: Option<LValueType>) -> Option<String> {
-        if let Some(lvalue) = lvalue {
-            if let LValueType::Symbol(sym) = lvalue {
-                return Option::Some(sym.to_owned());
-            }
+        if let Some(LValueType::Symbol(sym)) = lvalue {
+            return Option::Some(sym.to_owned());
         }
 
         return Option::None;
     }
 
     fn is_macro_start(lvalue: Option<LValueType>) -> bool {
-        if let Some(lvalue) = lvalue {
-            if let LValueType::Symbol(sym) = lvalue {
-                if sym == "macro" {
-                    return true;
-                }
+        if let Some(LValueType::Symbol(sym)) = lvalue {
+            if sym == "macro" {
+                return true;
             }
         }
 
         return false;
     }
 
-    fn clone_lvalue(lvalue: Option<&LValueType>) -> Option<LValueType> {
-        match lvalue {
-            None => None,
-            Some(value) => Some(value.to_owned()),
-        }
+    fn clone_lvalue(lvalue: Option<&LWord>) -> Option<LValueType> {
+        lvalue.map(|word| word.value.to_owned())
     }
 
     let mut it = raw_code.iter();
+    #[allow(clippy::while_let_on_iterator)] /* the body reads on from `it` too */
     while let Some(item) = it.next() {
-        if !is_macro_start(Option::Some(item.clone())) {
+        if !is_macro_start(Option::Some(item.value.clone())) {
             code.push(item.to_owned());
             continue;
         }
         
         let next = it.next();
+        let name_loc = next.map(|x| x.loc.clone()).unwrap_or_else(|| item.loc.clone());
         let mut macro_name: Option<String> = Option::None;
         match get_macro_text(clone_lvalue(next)) {
             Option::None => {
-                println!("Failed to lex macro! No name found!");
-                return code;
+                return Err(CompileError::MissingMacroName(item.loc.clone()));
             },
             Option::Some(text) => {
                 macro_name = Option::Some(text);
@@ -88,14 +129,15 @@ fn load_macros_and_expand(raw_code: Vec<LValueType>) -> Vec<LValueType> {
         }
 
         let macro_name = macro_name.unwrap();
+        let params = read_macro_params(&mut it)?;
 
-        let mut body: Vec<LValueType> = Vec::new();
+        let mut body: Vec<LWord> = Vec::new();
         let mut success: bool = false;
         let mut count: i32 = 0;
         while let Some(value) = it.next() {
-            if is_macro_end(Option::Some(value.to_owned()), &mut count) {
-                let mcro = LMacro::new(&macro_name, &body);
-                macros.insert(macro_name, mcro);
+            if is_macro_end(Option::Some(value.value.to_owned()), &mut count) {
+                let mcro = LMacro::new(&macro_name, &params, &body, &name_loc, &value.loc);
+                macros.insert(macro_name.clone(), mcro);
                 success = true;
                 break;
             } else {
@@ -104,47 +146,116 @@ fn load_macros_and_expand(raw_code: Vec<LValueType>) -> Vec<LValueType> {
         }
 
         if !success {
-            println!("Failed to read macro!");
-            return Vec::new();
+            return Err(CompileError::UnbalancedBlock(item.loc.clone(), format!("macro `{}` is never closed with `end`", macro_name)));
         }
     }
 
-    let mut result: Vec<LValueType> = Vec::new();
+    return Ok((code, macros));
+}
 
-    let mut it = code.iter();
-    while let Some(item) = it.next() {
-        match get_macro_text(Option::Some(item.clone())) {
-            None => {
-                result.push(item.to_owned());
-            },
-            Some(text) => {
-                if let Some(mcro) = macros.get(&text) {
-                    let mut expanded = mcro.expand(&macros, 8);
-                    result.append(&mut expanded);
-                } else {
-                    result.push(item.to_owned());
-                }
+/*
+    `macro name a b in ... end` gives the macro parameters `a` and `b`.
+    Without `in` right after a run of plain words, the body starts right
+    after the name and the macro takes no arguments. `in` means nothing
+    anywhere else, so it is free to use as a name.
+ */
+fn read_macro_params(it: &mut std::slice::Iter<LWord>) -> Result<Vec<String>, CompileError> {
+    let mut params: Vec<(&LLoc, &String)> = Vec::new();
+    let mut ahead = it.clone();
+    while let Some(word) = ahead.next() {
+        let sym = match &word.value {
+            LValueType::Symbol(sym) => sym,
+            _ => return Ok(Vec::new()),
+        };
+
+        if is_keyword(sym) {
+            return Ok(Vec::new());
+        }
+
+        if sym != "in" {
+            params.push((&word.loc, sym));
+            continue;
+        }
+
+        *it = ahead;
+        let mut names: Vec<String> = Vec::new();
+        for (loc, name) in params {
+            if names.contains(name) {
+                return Err(CompileError::DuplicateDefinition(loc.clone(), name.clone()));
             }
+            names.push(name.clone());
         }
+        return Ok(names);
     }
-    
-    return result;
+
+    return Ok(Vec::new());
+}
+
+fn is_keyword(sym: &str) -> bool {
+    matches!(sym, "if" | "else" | "while" | "do" | "end" | "macro" | "proc" | "memory" | "const" | "inc")
+}
+
+/*
+    The words that start a block closed by a matching `end`.
+ */
+pub fn opens_block(sym: &str) -> bool {
+    matches!(sym, "if" | "while" | "macro" | "proc" | "memory" | "const")
+}
+
+/*
+    The macros defined in the code, by name, without expanding anything.
+ */
+pub fn find_macros(tokens: &[LToken]) -> Result<HashMap<String, LMacro>, CompileError> {
+    let code: Vec<LWord> = tokens.iter().map(convert_token_to_lword).collect::<Result<_, _>>()?;
+    let (_, macros) = load_macros(code)?;
+    return Ok(macros);
 }
 
-pub fn load_and_lex_code(path: &str) -> Vec<LOpType> {
-    let code: Vec<LValueType> = load_code(path).iter().map(|x|convert_string_to_lvalue(x)).rev().collect();
-    let code = load_macros_and_expand(code);
+/*
+    The words left once every macro has been defined and expanded.
+ */
+pub fn expand_code(tokens: &[LToken]) -> Result<Vec<LWord>, CompileError> {
+    let code: Vec<LWord> = tokens.iter().map(convert_token_to_lword).collect::<Result<_, _>>()?;
+    return load_macros_and_expand(code);
+}
 
-    let mut result: Vec<LOpType> = Vec::new();
+pub fn lex_code(code: Vec<LWord>) -> Result<(Vec<LOp>, LMemory), CompileError> {
+    let mut result: Vec<LOp> = Vec::new();
 
     let mut ip = 0;
 
-    let mut stack: Vec<i32> = Vec::new();
+    let mut stack: Vec<usize> = Vec::new();
+
+    let mut procs: HashMap<String, u64> = HashMap::new();
+    let mut calls: Vec<usize> = Vec::new();
+    let mut memory = LMemory::new();
+    let mut consts: HashMap<String, i64> = HashMap::new();
+
+    /*
+        Procedures may be called before they are defined, so collect every
+        name up front and patch the call targets once all ips are known.
+     */
+    let mut proc_names: HashSet<String> = HashSet::new();
+    for pair in code.windows(2) {
+        if let (LValueType::Symbol(keyword), LValueType::Symbol(name)) = (&pair[0].value, &pair[1].value) {
+            if keyword == "proc" {
+                proc_names.insert(name.clone());
+            }
+        }
+    }
 
-    for value in code.iter() {
+    fn pop_block(stack: &mut Vec<usize>, loc: &LLoc, text: &str) -> Result<usize, CompileError> {
+        match stack.pop() {
+            Some(block_ip) => Ok(block_ip),
+            None => Err(CompileError::UnbalancedBlock(loc.clone(), text.to_string())),
+        }
+    }
+
+    let mut it = code.iter();
+    while let Some(word) = it.next() {
         
-        let op_type = match value {
-            LValueType::Number(x) => LOpType::Push(LValue::Number(x.clone())),
+        let op_type = match &word.value {
+            LValueType::Number(x) => LOpType::Push(LValue::Number(*x)),
             LValueType::Text(x) => LOpType::Push(LValue::Text(x.clone())),
             LValueType::Symbol(sym) => {
                 if sym == "add" || sym == "+" {
@@ -189,120 +300,563 @@ pub fn load_and_lex_code(path: &str) -> Vec<LOpType> {
                     stack.push(ip);
                     LOpType::If(0)
                 } else if (sym == "else") {
-                    let block_ip = stack.pop().unwrap_or(-1);
-                    let result_sym = result.get(block_ip as usize).unwrap();
-                    if let LOpType::If(x) = &result_sym {
-                        result[block_ip as usize] = LOpType::If((ip + 1) as u64);
+                    let block_ip = pop_block(&mut stack, &word.loc, "`else` without matching `if`")?;
+                    if let LOpType::If(x) = &result[block_ip].op {
+                        result[block_ip].op = LOpType::If((ip + 1) as u64);
                         stack.push(ip);
                         LOpType::Else(0)
                     } else {
-                        LOpType::Nop(format!("else/sym:{:?}", result_sym).to_string())
+                        return Err(CompileError::UnbalancedBlock(word.loc.clone(), "`else` without matching `if`".to_string()));
                     }
                 } else if (sym == "while") {
                     stack.push(ip);
                     LOpType::While
                 } else if (sym == "do") {
-                    let while_ip = stack.pop().unwrap_or(-1);
-                    stack.push(ip);
-                    LOpType::Do(while_ip as u64)
+                    let while_ip = pop_block(&mut stack, &word.loc, "`do` without matching `while`")?;
+                    if let LOpType::While = &result[while_ip].op {
+                        stack.push(ip);
+                        LOpType::Do(while_ip as u64)
+                    } else {
+                        return Err(CompileError::UnbalancedBlock(word.loc.clone(), "`do` without matching `while`".to_string()));
+                    }
                 } else if (sym == "end") {
-                    let block_ip = stack.pop().unwrap_or(-1);
-                    let op = result.get(block_ip as usize).unwrap();
+                    let block_ip = pop_block(&mut stack, &word.loc, "`end` without matching block")?;
+                    let op = &result[block_ip].op;
                     if let LOpType::If(x) = op.clone() {
-                        result[block_ip as usize] = LOpType::If((ip + 1) as u64);
+                        result[block_ip].op = LOpType::If((ip + 1) as u64);
                         LOpType::End((ip + 1) as u64)
                     } else if let LOpType::Else(x) = op.clone() {
-                        result[block_ip as usize] = LOpType::Else((ip + 1) as u64);
+                        result[block_ip].op = LOpType::Else((ip + 1) as u64);
                         LOpType::End((ip + 1) as u64)
                     } else if let LOpType::Do(x) = op.clone() {
-                        result[block_ip as usize] = LOpType::Do((ip + 1) as u64);
+                        result[block_ip].op = LOpType::Do((ip + 1) as u64);
                         LOpType::End(x)
+                    } else if let LOpType::Proc(name, x) = op.clone() {
+                        result[block_ip].op = LOpType::Proc(name, (ip + 1) as u64);
+                        LOpType::Ret
                     } else {
-                        LOpType::Nop(format!("end/sym:{:?}", op).to_string())
+                        return Err(CompileError::UnbalancedBlock(result[block_ip].loc.clone(), "`while` without `do`".to_string()));
+                    }
+                } else if (sym == "proc") {
+                    if !stack.is_empty() {
+                        return Err(CompileError::UnbalancedBlock(word.loc.clone(), "procedures can only be defined at the top level".to_string()));
+                    }
+
+                    let name = match it.next().map(|x| &x.value) {
+                        Some(LValueType::Symbol(name)) => name.clone(),
+                        _ => return Err(CompileError::MissingProcName(word.loc.clone())),
+                    };
+
+                    if is_intrinsic(&name) {
+                        return Err(CompileError::ReservedName(word.loc.clone(), name));
+                    }
+
+                    if procs.contains_key(&name) || memory.get(&name).is_some() || consts.contains_key(&name) {
+                        return Err(CompileError::DuplicateDefinition(word.loc.clone(), name));
+                    }
+
+                    procs.insert(name.clone(), ip as u64);
+                    stack.push(ip);
+                    LOpType::Proc(name, 0)
+                } else if (sym == "memory") {
+                    if !stack.is_empty() {
+                        return Err(CompileError::UnbalancedBlock(word.loc.clone(), "memory can only be declared at the top level".to_string()));
                     }
+
+                    let name = match it.next().map(|x| &x.value) {
+                        Some(LValueType::Symbol(name)) => name.clone(),
+                        _ => return Err(CompileError::MissingMemoryName(word.loc.clone())),
+                    };
+
+                    if is_intrinsic(&name) {
+                        return Err(CompileError::ReservedName(word.loc.clone(), name));
+                    }
+
+                    if memory.get(&name).is_some() || proc_names.contains(&name) || consts.contains_key(&name) {
+                        return Err(CompileError::DuplicateDefinition(word.loc.clone(), name));
+                    }
+
+                    let body = read_decl_body(&mut it, word, "memory")?;
+                    let size = eval_const_expr(&body, &consts, &word.loc)?;
+                    if size < 0 {
+                        return Err(CompileError::InvalidMemorySize(word.loc.clone(), name));
+                    }
+                    let size = size as u64;
+
+                    memory.alloc(&name, size, &word.loc)?;
+                    continue;
+                } else if (sym == "const") {
+                    let name = match it.next().map(|x| &x.value) {
+                        Some(LValueType::Symbol(name)) => name.clone(),
+                        _ => return Err(CompileError::MissingConstName(word.loc.clone())),
+                    };
+
+                    if is_intrinsic(&name) {
+                        return Err(CompileError::ReservedName(word.loc.clone(), name));
+                    }
+
+                    if memory.get(&name).is_some() || proc_names.contains(&name) || consts.contains_key(&name) {
+                        return Err(CompileError::DuplicateDefinition(word.loc.clone(), name));
+                    }
+
+                    let body = read_decl_body(&mut it, word, "const")?;
+                    let value = eval_const_expr(&body, &consts, &word.loc)?;
+                    consts.insert(name, value);
+                    continue;
+                } else if let Some(count) = sym.strip_prefix("syscall").and_then(|x| x.parse::<u8>().ok()).filter(|x| *x <= 6) {
+                    LOpType::Syscall(count)
+                } else if (sym == "exit") {
+                    LOpType::Exit
                 } else if (sym == "drop") {
                     LOpType::Drop
-                } else if (sym == "store" || sym == "S") {
-                    LOpType::Store
-                } else if (sym == "load" || sym == "L") {
-                    LOpType::Load
+                } else if (sym == "store" || sym == "S" || sym == "store8" || sym == "S8") {
+                    LOpType::Store(8)
+                } else if (sym == "store16" || sym == "S16") {
+                    LOpType::Store(16)
+                } else if (sym == "store32" || sym == "S32") {
+                    LOpType::Store(32)
+                } else if (sym == "store64" || sym == "S64") {
+                    LOpType::Store(64)
+                } else if (sym == "load" || sym == "L" || sym == "load8" || sym == "L8") {
+                    LOpType::Load(8, false)
+                } else if (sym == "load16" || sym == "L16") {
+                    LOpType::Load(16, false)
+                } else if (sym == "load32" || sym == "L32") {
+                    LOpType::Load(32, false)
+                } else if (sym == "load64" || sym == "L64") {
+                    LOpType::Load(64, false)
+                } else if (sym == "load8s" || sym == "L8s") {
+                    LOpType::Load(8, true)
+                } else if (sym == "load16s" || sym == "L16s") {
+                    LOpType::Load(16, true)
+                } else if (sym == "load32s" || sym == "L32s") {
+                    LOpType::Load(32, true)
                 } else if (sym == "@") {
                     LOpType::Mem
+                } else if (sym == "argc") {
+                    LOpType::Argc
+                } else if (sym == "argv") {
+                    LOpType::Argv
+                } else if (sym == "envp") {
+                    LOpType::Envp
                 } else if (sym == "P") {
                     LOpType::Puts(true)
                 } else if (sym == "p") {
                     LOpType::Puts(false)
+                } else if let Some(value) = consts.get(sym) {
+                    LOpType::Push(LValue::Number(*value))
+                } else if let Some(region) = memory.get(sym) {
+                    LOpType::Region(region.name.clone(), region.offset)
+                } else if proc_names.contains(sym) {
+                    calls.push(ip);
+                    LOpType::Call(sym.clone(), 0)
                 } else {
-                    LOpType::Nop(format!("lex:{}", sym).to_string())
+                    return Err(CompileError::UnknownWord(word.loc.clone(), sym.clone()));
                 }
             },
             LValueType::Char(x) => {
-                LOpType::Push(LValue::Number(x.clone()))
+                LOpType::Push(LValue::Number(*x))
+            },
+            LValueType::None => {
+                return Err(CompileError::UnknownWord(word.loc.clone(), String::from("_")));
             },
-            LValueType::None => LOpType::Nop(String::from("Invalid token type!")),
         };
 
-        result.push(op_type);
+        result.push(LOp::new(op_type, &word.value.text(), &word.loc));
 
         ip += 1;
     }
 
+    if let Some(block_ip) = stack.pop() {
+        let name = match &result[block_ip].op {
+            LOpType::If(_) => "if",
+            LOpType::Else(_) => "else",
+            LOpType::While => "while",
+            LOpType::Do(_) => "do",
+            LOpType::Proc(_, _) => "proc",
+            _ => "block",
+        };
+        return Err(CompileError::UnbalancedBlock(result[block_ip].loc.clone(), format!("`{}` is never closed with `end`", name)));
+    }
+
+    for call_ip in calls {
+        if let LOpType::Call(name, _) = &result[call_ip].op {
+            let proc_ip = procs[name];
+            result[call_ip].op = LOpType::Call(name.clone(), proc_ip);
+        }
+    }
+
     result.reverse();
-    return result;
+    return Ok((result, memory));
 }
 
-fn load_code_file(path: &str) -> String {
-    let input = File::open(path).unwrap();
-    let buffered = BufReader::new(input);
-
-    let mut text = String::new();
-    for line in buffered.lines() {
-        match line {
-            Ok(x) => {
-                if x.starts_with("inc ") {
-                    let sub_text = load_code_file(format!("{}.ktnck", &x[4..]).as_str());
-                    text.push_str(format!(" {}", sub_text).as_str());
-                } else {
-                    text.push_str(format!(" {}", x).as_str());
+/*
+    Collects the words of a `memory` or `const` declaration up to its `end`.
+ */
+fn read_decl_body<'a>(it: &mut std::slice::Iter<'a, LWord>, start: &LWord, kind: &str) -> Result<Vec<&'a LWord>, CompileError> {
+    let mut body: Vec<&LWord> = Vec::new();
+    for word in it.by_ref() {
+        if let LValueType::Symbol(sym) = &word.value {
+            if sym == "end" {
+                return Ok(body);
+            }
+        }
+        body.push(word);
+    }
+
+    return Err(CompileError::UnbalancedBlock(start.loc.clone(), format!("`{}` is never closed with `end`", kind)));
+}
+
+/*
+    Evaluates a constant expression the same way the program would at
+    runtime, but only over literals, constants and pure stack operations.
+    The expression has to leave exactly one value.
+ */
+fn eval_const_expr(body: &[&LWord], consts: &HashMap<String, i64>, loc: &LLoc) -> Result<i64, CompileError> {
+    let mut stack: Vec<u64> = Vec::new();
+
+    for word in body.iter() {
+        let sym = match &word.value {
+            LValueType::Number(x) | LValueType::Char(x) => {
+                stack.push(*x as u64);
+                continue;
+            },
+            LValueType::Symbol(sym) => sym,
+            _ => return Err(CompileError::InvalidConstExpr(word.loc.clone(), format!("{} is not a constant", word.value))),
+        };
+
+        if let Some(value) = consts.get(sym) {
+            stack.push(*value as u64);
+            continue;
+        }
+
+        let ins = match sym.as_str() {
+            "dup" | "drop" => 1,
+            "swap" | "s" | "over" => 2,
+            "+" | "add" | "-" | "sub" | "*" | "mul" | "/" | "div" | "%" | "mod" => 2,
+            "<<" | "shl" | ">>" | "shr" | "|" | "bor" | "&" | "band" => 2,
+            "<" | ">" | "<=" | ">=" | "=" | "!=" => 2,
+            _ => return Err(CompileError::InvalidConstExpr(word.loc.clone(), format!("`{}` cannot be used in a constant", sym))),
+        };
+
+        if stack.len() < ins {
+            return Err(CompileError::InvalidConstExpr(word.loc.clone(), format!("`{}` needs {} value(s)", sym, ins)));
+        }
+
+        if ins == 1 {
+            let a = stack.pop().unwrap();
+            if sym == "dup" {
+                stack.push(a);
+                stack.push(a);
+            }
+            continue;
+        }
+
+        let b = stack.pop().unwrap();
+        let a = stack.pop().unwrap();
+        let value = match sym.as_str() {
+            "swap" | "s" => {
+                stack.push(b);
+                a
+            },
+            "over" => {
+                stack.push(a);
+                stack.push(b);
+                a
+            },
+            "+" | "add" => a.wrapping_add(b),
+            "-" | "sub" => a.wrapping_sub(b),
+            "*" | "mul" => a.wrapping_mul(b),
+            "/" | "div" | "%" | "mod" => {
+                if b == 0 {
+                    return Err(CompileError::InvalidConstExpr(word.loc.clone(), "division by zero".to_string()));
                 }
+                if sym == "/" || sym == "div" { a / b } else { a % b }
             },
-            Err(_) => panic!(),
+            "<<" | "shl" => a << (b & 63),
+            ">>" | "shr" => a >> (b & 63),
+            "|" | "bor" => a | b,
+            "&" | "band" => a & b,
+            "<" => ((a as i64) < (b as i64)) as u64,
+            ">" => ((a as i64) > (b as i64)) as u64,
+            "<=" => ((a as i64) <= (b as i64)) as u64,
+            ">=" => ((a as i64) >= (b as i64)) as u64,
+            "=" => (a == b) as u64,
+            _ => (a != b) as u64,
+        };
+        stack.push(value);
+    }
+
+    if stack.len() != 1 {
+        return Err(CompileError::InvalidConstExpr(loc.clone(), format!("expression leaves {} values instead of one", stack.len())));
+    }
+
+    return Ok(stack[0] as i64);
+}
+
+fn is_quote(c: char) -> bool {
+    c == '"' || c == '\''
+}
+
+fn starts_with_at(chars: &[char], i: usize, pattern: &str) -> bool {
+    for (j, c) in (i..).zip(pattern.chars()) {
+        if j >= chars.len() || chars[j] != c {
+            return false;
         }
     }
 
-    return text.trim_start().to_owned();
+    return true;
 }
 
-fn get_code_words(text: &String) -> Vec<String> {
-    let mut words: Vec<String> = text.trim_start()
-        .split(" ")
-        .map(|p| p.trim())
-        .filter(|p| !p.is_empty())
-        .map(String::from)
-        .collect();
-    words.reverse();
-    words
+fn is_comment_start(chars: &[char], i: usize) -> bool {
+    starts_with_at(chars, i, "//") || starts_with_at(chars, i, "/*")
 }
 
-fn get_code_words_and_strings(text: &String) -> Vec<String> {
-    let mut code = get_code_words(text);
-    let mut result: Vec<String> = Vec::new();
+/*
+    Reads source text a line at a time into words and, when `keep_comments`
+    is set, comments, each with where it starts. String and character
+    literals are kept as a single word, quotes and escapes included, so they
+    may contain spaces. Lines and columns are 1-based and counted in chars.
+
+    `//` comments run to the end of the line and `/* */` comments may span
+    lines and nest. Neither is recognized inside a literal. A block comment
+    spanning several lines belongs to the line it starts on, with its line
+    breaks kept, and the words after it on its last line follow it.
+ */
+fn scan_source(text: &str, file: &str, keep_comments: bool) -> Result<Vec<Vec<(LPiece, LLoc)>>, CompileError> {
+    let mut lines: Vec<Vec<(LPiece, LLoc)>> = Vec::new();
+    let mut pieces: Vec<(LPiece, LLoc)> = Vec::new();
+    let mut comment: Option<(String, LLoc, usize)> = None;
+
+    for (line_index, line) in text.lines().enumerate() {
+        let chars: Vec<char> = line.chars().collect();
+        let mut i = 0;
+
+        if let Some((text, _, _)) = comment.as_mut() {
+            text.push('\n');
+        }
+
+        while i < chars.len() {
+            if let Some((text, _, depth)) = comment.as_mut() {
+                if starts_with_at(&chars, i, "/*") {
+                    *depth += 1;
+                    text.push_str("/*");
+                    i += 2;
+                } else if starts_with_at(&chars, i, "*/") {
+                    *depth -= 1;
+                    text.push_str("*/");
+                    i += 2;
+                } else {
+                    text.push(chars[i]);
+                    i += 1;
+                }
+
+                if *depth == 0 {
+                    let (text, loc, _) = comment.take().unwrap();
+                    if keep_comments {
+                        pieces.push((LPiece::Comment(text), loc));
+                    }
+                }
+                continue;
+            }
+
+            if chars[i].is_whitespace() {
+                i += 1;
+                continue;
+            }
+
+            if starts_with_at(&chars, i, "//") {
+                if keep_comments {
+                    let text = chars[i..].iter().collect::<String>().trim_end().to_string();
+                    pieces.push((LPiece::Comment(text), LLoc::new(file, line_index + 1, i + 1)));
+                }
+                break;
+            }
+
+            if starts_with_at(&chars, i, "/*") {
+                comment = Some(("/*".to_string(), LLoc::new(file, line_index + 1, i + 1), 1));
+                i += 2;
+                continue;
+            }
+
+            let start = i;
+            if is_quote(chars[i]) {
+                let quote = chars[i];
+                i += 1;
+                while i < chars.len() && chars[i] != quote {
+                    if chars[i] == '\\' {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+
+                if i >= chars.len() {
+                    return Err(CompileError::UnterminatedString(LLoc::new(file, line_index + 1, start + 1)));
+                }
+                i += 1;
+            } else {
+                while i < chars.len() && !chars[i].is_whitespace() && !is_comment_start(&chars, i) {
+                    i += 1;
+                }
+            }
+
+            pieces.push((LPiece::Word(chars[start..i].iter().collect()), LLoc::new(file, line_index + 1, start + 1)));
+        }
 
-    while let Some(value) = code.pop() {
-        result.push(fetch_string(&value, &mut code));
+        if comment.is_none() {
+            lines.push(std::mem::take(&mut pieces));
+        }
     }
 
-    result.reverse();
+    if let Some((_, loc, _)) = comment {
+        return Err(CompileError::UnterminatedComment(loc));
+    }
 
-    result
+    Ok(lines)
 }
 
-pub fn load_code(path: &str) -> Vec<String> {
-    let text = load_code_file(path);
+/*
+    Splits source text into whitespace separated tokens, leaving out
+    comments.
+ */
+pub fn get_code_tokens(text: &str, file: &str) -> Result<Vec<LToken>, CompileError> {
+    let lines = scan_source(text, file, false)?;
+    let tokens = lines.into_iter().flatten().filter_map(|(piece, loc)| match piece {
+        LPiece::Word(text) => Some(LToken { text, loc }),
+        LPiece::Comment(_) => None,
+    }).collect();
+
+    Ok(tokens)
+}
+
+/*
+    Splits source text into lines of words and comments, keeping comments
+    and literals exactly as written. An empty line has no pieces.
+ */
+pub fn get_source_lines(text: &str, file: &str) -> Result<Vec<Vec<LPiece>>, CompileError> {
+    let lines = scan_source(text, file, true)?;
+    Ok(lines.into_iter().map(|line| line.into_iter().map(|(piece, _)| piece).collect()).collect())
+}
+
+/*
+    Where `inc` looks for files: next to the including file first, then the
+    -I directories in order, then the ones listed in KTNACK_PATH. Every file
+    is read at most once, `chain` holds the includes currently being loaded.
+ */
+#[derive(Clone)]
+pub struct Includes {
+    search: Vec<PathBuf>,
+    loaded: HashSet<PathBuf>,
+    chain: Vec<(PathBuf, String)>,
+}
+
+impl Includes {
+    pub fn new(dirs: &[String]) -> Self {
+        let mut search: Vec<PathBuf> = dirs.iter().map(PathBuf::from).collect();
+        if let Some(paths) = env::var_os("KTNACK_PATH") {
+            search.extend(env::split_paths(&paths).filter(|x| !x.as_os_str().is_empty()));
+        }
+
+        Self {
+            search,
+            loaded: HashSet::new(),
+            chain: Vec::new(),
+        }
+    }
+
+    fn resolve(&self, name: &str, from: &str) -> Option<PathBuf> {
+        let file_name = format!("{}.ktnck", name);
+        let local = Path::new(from).parent().unwrap_or(Path::new("")).join(&file_name);
 
-    let code_tokens = get_code_words_and_strings(&text);
+        let mut candidates = vec![local];
+        candidates.extend(self.search.iter().map(|dir| dir.join(&file_name)));
+        candidates.into_iter().find(|x| x.is_file())
+    }
 
-    println!("Code tokens: {}", code_tokens.len());
+    fn load(&mut self, path: &str, site: Option<&LLoc>) -> Result<Vec<LToken>, CompileError> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(error) => {
+                return Err(match site {
+                    Some(loc) => CompileError::MissingInclude(loc.clone(), path.to_string()),
+                    None => CompileError::Io(path.to_string(), error.to_string()),
+                });
+            }
+        };
 
-    code_tokens
-}
\ No newline at end of file
+        return self.load_text(path, &text, site);
+    }
+
+    fn load_text(&mut self, path: &str, text: &str, site: Option<&LLoc>) -> Result<Vec<LToken>, CompileError> {
+        let key = fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+        if self.chain.iter().any(|(x, _)| *x == key) {
+            let mut files: Vec<String> = self.chain.iter().map(|(_, name)| name.clone()).collect();
+            files.push(path.to_string());
+            return Err(CompileError::IncludeCycle(site.unwrap().clone(), files.join(" -> ")));
+        }
+
+        if !self.loaded.insert(key.clone()) {
+            return Ok(Vec::new());
+        }
+
+        let tokens = get_code_tokens(text, path)?;
+
+        self.chain.push((key, path.to_string()));
+        let result = self.include_all(tokens, path)?;
+        self.chain.pop();
+
+        Ok(result)
+    }
+
+    /*
+        Loads a piece of code that isn't a file of its own, such as a line
+        typed into the REPL. Files it includes still count as loaded
+        afterwards, so a later `inc` of the same file does nothing.
+     */
+    pub fn load_snippet(&mut self, path: &str, text: &str) -> Result<Vec<LToken>, CompileError> {
+        let tokens = get_code_tokens(text, path)?;
+        return self.include_all(tokens, path);
+    }
+
+    fn include_all(&mut self, tokens: Vec<LToken>, path: &str) -> Result<Vec<LToken>, CompileError> {
+        let mut result: Vec<LToken> = Vec::new();
+        let mut it = tokens.into_iter();
+        while let Some(token) = it.next() {
+            if token.text != "inc" {
+                result.push(token);
+                continue;
+            }
+
+            let name = match it.next() {
+                Some(name) => name,
+                None => return Err(CompileError::MissingInclude(token.loc.clone(), String::new())),
+            };
+
+            let found = match self.resolve(&name.text, path) {
+                Some(found) => found,
+                None => return Err(CompileError::MissingInclude(token.loc.clone(), format!("{}.ktnck", name.text))),
+            };
+
+            let mut sub_tokens = self.load(found.to_string_lossy().as_ref(), Some(&token.loc))?;
+            result.append(&mut sub_tokens);
+        }
+
+        Ok(result)
+    }
+}
+
+pub fn load_code(path: &str, include_dirs: &[String]) -> Result<Vec<LToken>, CompileError> {
+    let code_tokens = Includes::new(include_dirs).load(path, None)?;
+
+    debugln!("Code tokens: {}", code_tokens.len());
+
+    Ok(code_tokens)
+}
+
+/*
+    Like load_code, for a file whose text is already in memory, such as one
+    being edited. Includes are still read from disk.
+ */
+pub fn load_code_text(path: &str, text: &str, include_dirs: &[String]) -> Result<Vec<LToken>, CompileError> {
+    return Includes::new(include_dirs).load_text(path, text, None);
+}
@@ -1,17 +1,30 @@
-pub const IS_DEBUG: bool = false;
-
 use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/*
+    Turned on with --debug, so the extra output needs no rebuild. It goes
+    to stderr, leaving stdout to the program and to --emit.
+ */
+static IS_DEBUG: AtomicBool = AtomicBool::new(false);
+
+pub fn set_debug(value: bool) {
+    IS_DEBUG.store(value, Ordering::Relaxed);
+}
+
+pub fn is_debug() -> bool {
+    IS_DEBUG.load(Ordering::Relaxed)
+}
 
 #[macro_export]
 macro_rules! debugln {
     () => {
-        if IS_DEBUG {
-            println!()
+        if $crate::utils::is_debug() {
+            eprintln!()
         }
     };
     ($($arg:tt)*) => {{
-        if IS_DEBUG {
-            println!($($arg)*);
+        if $crate::utils::is_debug() {
+            eprintln!($($arg)*);
         }
     }};
 }